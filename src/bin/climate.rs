@@ -7,6 +7,8 @@ use std::path::Path;
 use std::process;
 use structopt::StructOpt;
 
+mod common;
+
 use esera_mqtt::climate::{Climate, Conf, BASE};
 use esera_mqtt::{MqttMsg, Routes, Token};
 
@@ -15,9 +17,23 @@ struct Opt {
     /// MQTT broker address
     #[structopt(short = "H", long, default_value = "localhost", env = "MQTT_HOST")]
     mqtt_host: String,
+    /// MQTT broker port
+    #[structopt(short = "P", long, default_value = "1883", env = "MQTT_PORT")]
+    mqtt_port: u16,
     /// MQTT credentials (username:password)
     #[structopt(short = "C", long, default_value = "", env = "MQTT_CRED")]
     mqtt_cred: String,
+    /// QoS level for heating commands
+    #[structopt(short = "Q", long, default_value = "0")]
+    qos_command: u8,
+    /// QoS level for thermostat states
+    #[structopt(long, default_value = "0")]
+    qos_state: u8,
+    /// QoS level for discovery announcements
+    #[structopt(long, default_value = "0")]
+    qos_discovery: u8,
+    #[structopt(flatten)]
+    tls: common::TlsOpt,
     #[structopt(value_name = "PATH")]
     config: String,
 }
@@ -87,10 +103,21 @@ impl HVACs {
 fn run(opt: Opt, log: &Logger) -> Result<()> {
     let configs = Configs::read(&opt.config)
         .with_context(|| format!("Failed to read config file {}", opt.config))?;
+    let mut conf = esera_mqtt::config::Mqtt {
+        host: opt.mqtt_host.clone(),
+        port: opt.mqtt_port,
+        cred: opt.mqtt_cred.clone(),
+        qos: esera_mqtt::config::Qos {
+            state: opt.qos_state,
+            command: opt.qos_command,
+            discovery: opt.qos_discovery,
+        },
+        ..Default::default()
+    };
+    opt.tls.apply(&mut conf);
     let (mut mqtt, recv) = esera_mqtt::MqttConnection::new(
-        &opt.mqtt_host,
-        &opt.mqtt_cred,
-        &format!("{}/status", BASE),
+        &conf,
+        format!("{}/status", BASE),
         log.new(o!("mqtt" => opt.mqtt_host.clone())),
     )
     .context("Failed to connect to MQTT broker")?;
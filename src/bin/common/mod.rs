@@ -0,0 +1,74 @@
+//! Command line options shared by the binaries
+use esera_mqtt::config::{Mqtt, Tls};
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+/// Command line options for MQTT TLS settings
+#[derive(Debug, Clone, Default, StructOpt)]
+pub struct TlsOpt {
+    /// CA certificate file (PEM). Enables TLS.
+    #[structopt(long, value_name = "PATH", env = "MQTT_CA")]
+    pub mqtt_ca: Option<PathBuf>,
+    /// Client certificate file (PEM)
+    #[structopt(long, value_name = "PATH", env = "MQTT_CERT")]
+    pub mqtt_cert: Option<PathBuf>,
+    /// Client private key file (PEM)
+    #[structopt(long, value_name = "PATH", env = "MQTT_KEY")]
+    pub mqtt_key: Option<PathBuf>,
+    /// ALPN protocol (may be given multiple times)
+    #[structopt(long, value_name = "PROTO")]
+    pub mqtt_alpn: Vec<String>,
+}
+
+impl TlsOpt {
+    /// Overrides TLS settings in `conf` with command line options.
+    pub fn apply(&self, conf: &mut Mqtt) {
+        if let Some(ca) = &self.mqtt_ca {
+            conf.tls = Some(Tls {
+                ca: ca.clone(),
+                client_cert: None,
+                client_key: None,
+                alpn: Vec::new(),
+            })
+        }
+        if let Some(tls) = &mut conf.tls {
+            if self.mqtt_cert.is_some() {
+                tls.client_cert = self.mqtt_cert.clone();
+            }
+            if self.mqtt_key.is_some() {
+                tls.client_key = self.mqtt_key.clone();
+            }
+            if !self.mqtt_alpn.is_empty() {
+                tls.alpn = self.mqtt_alpn.clone();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tls_opt_overrides_config() {
+        let mut conf: Mqtt = toml::from_str(
+            r#"
+[tls]
+ca = "ca.pem"
+client_cert = "client.pem"
+client_key = "client.key"
+alpn = ["mqtt"]
+"#,
+        )
+        .unwrap();
+        TlsOpt {
+            mqtt_key: Some("other.key".into()),
+            ..Default::default()
+        }
+        .apply(&mut conf);
+        let tls = conf.tls.unwrap();
+        assert_eq!(tls.ca, PathBuf::from("ca.pem"));
+        assert_eq!(tls.client_key, Some("other.key".into()));
+        assert_eq!(tls.alpn, vec!["mqtt"]);
+    }
+}
@@ -2,154 +2,439 @@
 extern crate log;
 
 use anyhow::{Context, Result};
+use chrono::Local;
 use crossbeam::channel::{self, Receiver, Sender};
-use std::fmt;
-use std::net::ToSocketAddrs;
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 use structopt::StructOpt;
 use thiserror::Error;
+use tokio::runtime::{Handle, Runtime};
+use tokio::sync::mpsc;
+use tokio::time::sleep;
 
+mod common;
+
+use esera_mqtt::capture::Recorder;
 use esera_mqtt::{
-    Bus, ControllerConnection, ControllerError, Device, MqttConnection, MqttMsg, Routes, OW,
+    Bus, Command, Config, ControllerConnection, ControllerError, Device, MqttConnection, MqttMsg,
+    Reply, Request, Routes, OW,
 };
 
+const DEFAULT_PORT: u16 = 5000;
+
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("Controller channel closed")]
     ChanClosed,
     #[error("MQTT broker connection closed")]
     MqttClosed,
+    #[error("No controllers configured")]
+    NoControllers,
 }
 
 #[derive(StructOpt, Clone, Debug)]
 struct Opt {
-    /// Host name or IP address of a ESERA controller
+    /// Host names or IP addresses of ESERA controllers
     ///
     /// Can optionally contain a port number separated with ":". If no port number is given, the
-    /// default port number applies.
+    /// default port number applies. Overrides controllers from the config file.
     #[structopt(value_name = "HOST|IP[:PORT]")]
-    controller: String,
-    /// Port number
-    #[structopt(short = "p", long, default_value = "5000")]
-    default_port: u16,
-    /// MQTT broker address
-    #[structopt(short = "H", long, default_value = "localhost", env = "MQTT_HOST")]
-    mqtt_host: String,
+    controllers: Vec<String>,
+    /// Config file (TOML)
+    #[structopt(short = "c", long, value_name = "PATH", env = "ESERA_CONFIG")]
+    config: Option<PathBuf>,
+    /// Port number [default: 5000]
+    #[structopt(short = "p", long)]
+    default_port: Option<u16>,
+    /// MQTT broker address [default: localhost]
+    #[structopt(short = "H", long, env = "MQTT_HOST")]
+    mqtt_host: Option<String>,
+    /// MQTT broker port [default: 1883]
+    #[structopt(short = "P", long, env = "MQTT_PORT")]
+    mqtt_port: Option<u16>,
     /// MQTT credentials (username:password)
-    #[structopt(short = "C", long, default_value = "", env = "MQTT_CRED")]
-    mqtt_cred: String,
+    #[structopt(short = "C", long, env = "MQTT_CRED")]
+    mqtt_cred: Option<String>,
+    /// Remove retained discovery announcements of devices which are not present anymore
+    #[structopt(long)]
+    purge_discovery: bool,
+    /// Base of all MQTT topics [default: ESERA]
+    #[structopt(long, env = "ESERA_PREFIX")]
+    prefix: Option<String>,
+    /// Record controller sessions into this directory
+    #[structopt(long, value_name = "DIR")]
+    record: Option<PathBuf>,
+    #[structopt(flatten)]
+    tls: common::TlsOpt,
+}
+
+impl Opt {
+    /// Reads config file (if any) and applies command line overrides.
+    fn config(&self) -> Result<Config> {
+        let mut conf = match &self.config {
+            Some(path) => Config::read(path)?,
+            None => Config::default(),
+        };
+        if !self.controllers.is_empty() {
+            conf.controllers = self.controllers.clone();
+        }
+        if self.default_port.is_some() {
+            conf.default_port = self.default_port;
+        }
+        if let Some(host) = &self.mqtt_host {
+            conf.mqtt.host = host.clone();
+        }
+        if let Some(port) = self.mqtt_port {
+            conf.mqtt.port = port;
+        }
+        if let Some(cred) = &self.mqtt_cred {
+            conf.mqtt.cred = cred.clone();
+        }
+        if self.purge_discovery {
+            conf.discovery.purge = true;
+        }
+        if let Some(prefix) = &self.prefix {
+            conf.mqtt.prefix = prefix.clone();
+        }
+        if self.record.is_some() {
+            conf.record_dir = self.record.clone();
+        }
+        self.tls.apply(&mut conf.mqtt);
+        if conf.controllers.is_empty() {
+            return Err(Error::NoControllers.into());
+        }
+        Ok(conf)
+    }
 }
 
-type ChannelPair<O, I> = (Sender<O>, Receiver<I>);
-
-fn ctrl_loop<'a, A>(addr: A) -> Result<ChannelPair<String, Result<OW, ControllerError>>>
-where
-    A: ToSocketAddrs + Clone + fmt::Debug + Send + 'a,
-{
-    let (up_tx, up_rx) = channel::unbounded();
-    let (down_tx, down_rx) = channel::unbounded();
-    let mut c = ControllerConnection::new(addr)?;
-    // this is going to trigger registration which will be handled via ordinary event processing
-    down_tx.send(c.csi()).ok();
-    down_tx.send(c.list()).ok();
-    thread::spawn(move || {
-        if let Err(e) = c.event_loop(up_rx, down_tx) {
-            error!("[{}] Controller event loop died: {}", c.contno, e)
+/// Starts a new capture file for a controller connection, if recording is enabled.
+fn recorder(addr: &str, conf: &Config) -> Option<Recorder> {
+    let dir = conf.record_dir.as_ref()?;
+    let now = Local::now();
+    let path = dir.join(format!(
+        "{}-{}.cap",
+        addr.replace(|c: char| !c.is_ascii_alphanumeric() && c != '.', "_"),
+        now.format("%Y%m%d-%H%M%S")
+    ));
+    match Recorder::create(
+        &path,
+        &format!("{} {}", addr, now.format("%Y-%m-%d %H:%M:%S")),
+    ) {
+        Ok(rec) => {
+            info!("Recording session with {} to {}", addr, path.display());
+            Some(rec)
+        }
+        Err(e) => {
+            error!("Cannot record to {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Connects to a single controller and keeps the connection alive. Lost connections are
+/// re-established in the background. Each (re-)connect triggers a CSI/LST3 sequence which causes
+/// the bus to be initialized via ordinary event processing.
+fn ctrl_loop(
+    rt: &Handle,
+    addr: String,
+    conf: Arc<Config>,
+) -> (
+    mpsc::UnboundedSender<Request>,
+    Receiver<Result<OW, ControllerError>>,
+) {
+    let (up_tx, mut up_rx) = mpsc::unbounded_channel();
+    let (down_tx, down_rx) = channel::unbounded::<Result<OW, ControllerError>>();
+    rt.spawn(async move {
+        loop {
+            let rec = || recorder(&addr, &conf);
+            let conn = if addr.find(':').is_some() {
+                ControllerConnection::connect(addr.as_str(), &conf.timing, rec).await
+            } else {
+                ControllerConnection::connect(
+                    (addr.as_str(), conf.default_port.unwrap_or(DEFAULT_PORT)),
+                    &conf.timing,
+                    rec,
+                )
+                .await
+            };
+            match conn {
+                Ok(mut c) => {
+                    if down_tx.send(c.csi().await).is_err()
+                        || down_tx.send(c.list().await).is_err()
+                        || down_tx.send(c.settings().await).is_err()
+                    {
+                        return;
+                    }
+                    if let Err(e) = c.event_loop(&mut up_rx, &down_tx, &conf.timing).await {
+                        error!("[{}] Controller event loop died: {}", c.contno, e)
+                    }
+                }
+                Err(e) => error!("Failed to connect to controller {}: {}", addr, e),
+            }
+            if down_tx.send(Err(ControllerError::Disconnected)).is_err() {
+                // main loop has gone away
+                return;
+            }
+            warn!(
+                "Connection to controller {} lost, retrying in {}s",
+                addr, conf.timing.reconnect
+            );
+            sleep(Duration::from_secs(conf.timing.reconnect)).await;
         }
     });
-    Ok((up_tx, down_rx))
+    (up_tx, down_rx)
 }
 
-struct App {
-    opt: Opt,
-    ctrl_tx: Sender<String>,
-    ctrl_rx: Receiver<Result<OW, ControllerError>>,
+/// Looks up the error topic of the device which has issued each command.
+fn error_topics(bus: &Bus, replies: Vec<(usize, Command, Reply)>) -> Vec<(String, Command, Reply)> {
+    replies
+        .into_iter()
+        .map(|(dev, cmd, reply)| {
+            let dev = bus.devices.get(dev).unwrap_or(&bus.devices[0]);
+            (dev.info().error_topic(), cmd, reply)
+        })
+        .collect()
+}
+
+/// State associated with a single controller
+struct Ctrl {
+    tx: mpsc::UnboundedSender<Request>,
+    rx: Receiver<Result<OW, ControllerError>>,
     bus: Bus,
     routes: Routes<usize>,
+    /// Keepalive messages have been missed
+    degraded: bool,
+}
+
+struct App {
+    ctrls: Vec<Ctrl>,
+    mqtt: MqttConnection,
+    mqtt_chan: Receiver<MqttMsg>,
+    /// Home Assistant status topic, if discovery is enabled
+    birth: Option<String>,
+    rt: Handle,
+    /// Error reports of failed controller commands
+    feedback: (Sender<MqttMsg>, Receiver<MqttMsg>),
 }
 
 impl App {
-    fn new(opt: &Opt) -> Result<Self> {
-        let (ctrl_tx, ctrl_rx) = if opt.controller.find(':').is_some() {
-            ctrl_loop(opt.controller.as_str())
+    /// Takes over `buses` (one per configured controller). They are handed back by
+    /// [`into_buses`](Self::into_buses) so that device state survives reconnects.
+    fn new(conf: &Arc<Config>, rt: &Handle, buses: &mut Vec<Bus>) -> Result<Self> {
+        let (mut mqtt, mqtt_chan) =
+            MqttConnection::new(&conf.mqtt, conf.prefix().status_topic(), None)
+                .context("Failed to connect to MQTT broker")?;
+        let birth = if conf.discovery.enabled {
+            let topic = conf.prefix().birth_topic();
+            mqtt.subscribe(&topic)?;
+            Some(topic)
         } else {
-            ctrl_loop((opt.controller.as_str(), opt.default_port))
-        }
-        .context("Failed to set up initial controller connection")?;
+            None
+        };
+        let ctrls = conf
+            .controllers
+            .iter()
+            .zip(buses.drain(..))
+            .map(|(addr, bus)| {
+                let (tx, rx) = ctrl_loop(rt, addr.clone(), conf.clone());
+                Ctrl {
+                    tx,
+                    rx,
+                    bus,
+                    routes: Routes::new(),
+                    degraded: false,
+                }
+            })
+            .collect();
         Ok(Self {
-            opt: opt.clone(),
-            ctrl_tx,
-            ctrl_rx,
-            bus: Bus::default(),
-            routes: Routes::new(),
+            ctrls,
+            mqtt,
+            mqtt_chan,
+            birth,
+            rt: rt.clone(),
+            feedback: channel::unbounded(),
         })
     }
 
+    fn into_buses(self) -> Vec<Bus> {
+        self.ctrls.into_iter().map(|c| c.bus).collect()
+    }
+
+    /// Waits in the background for the outcome of controller commands and reports failures to
+    /// the error topic of the respective issuing device.
+    fn watch(&self, replies: Vec<(String, Command, Reply)>) {
+        for (err_topic, cmd, reply) in replies {
+            let feedback = self.feedback.0.clone();
+            self.rt.spawn(async move {
+                match reply.await {
+                    Ok(Err(ControllerError::Controller(code))) => {
+                        warn!("Controller rejected command {} (error {})", cmd, code);
+                        let payload =
+                            serde_json::json!({"command": cmd.to_string(), "error": code});
+                        feedback.send(MqttMsg::new(err_topic, payload)).ok();
+                    }
+                    Ok(Err(e)) => warn!("Command {} failed: {}", cmd, e),
+                    Ok(Ok(_)) => (),
+                    Err(_) => debug!("Lost track of command {}", cmd),
+                }
+            });
+        }
+    }
+
+    fn handle_ctrl(&mut self, i: usize, resp: Result<OW, ControllerError>) -> Result<()> {
+        let c = &mut self.ctrls[i];
+        match resp {
+            Ok(resp) => {
+                if c.degraded {
+                    info!("[{}] Controller is responsive again", c.bus.contno);
+                    c.degraded = false;
+                    self.mqtt.send(MqttMsg::retain(
+                        c.bus.devices[0].info().ctrl_status(),
+                        "online",
+                    ))?;
+                }
+                let replies = c
+                    .bus
+                    .handle_1wire(resp, &mut c.routes)?
+                    .send(&mut self.mqtt, &c.tx)?;
+                let replies = error_topics(&c.bus, replies);
+                self.watch(replies);
+            }
+            Err(ControllerError::Disconnected) if c.bus.contno > 0 => {
+                warn!("[{}] Controller offline", c.bus.contno);
+                self.mqtt.send(MqttMsg::retain(
+                    c.bus.devices[0].info().ctrl_status(),
+                    "offline",
+                ))?
+            }
+            Err(ControllerError::Keepalive(n)) if c.bus.contno > 0 => {
+                warn!(
+                    "[{}] Controller degraded ({} keepalives missed)",
+                    c.bus.contno, n
+                );
+                c.degraded = true;
+                self.mqtt.send(MqttMsg::retain(
+                    c.bus.devices[0].info().ctrl_status(),
+                    "degraded",
+                ))?
+            }
+            Err(e) => warn!("[{}] Controller read: {}", c.bus.contno, e),
+        }
+        Ok(())
+    }
+
+    fn handle_mqtt(&mut self, msg: MqttMsg) -> Result<()> {
+        match msg {
+            MqttMsg::Pub {
+                ref topic,
+                ref payload,
+                ..
+            } if Some(topic) == self.birth.as_ref() && payload == "online" => {
+                info!("Home Assistant online, republishing devices");
+                for c in &mut self.ctrls {
+                    self.mqtt.sendall(c.bus.republish().into_iter())?;
+                }
+            }
+            MqttMsg::Pub {
+                ref topic,
+                ref payload,
+                ..
+            } => {
+                let mut replies = Vec::new();
+                for c in &mut self.ctrls {
+                    for (dev, tok) in c.routes.lookup(topic) {
+                        let r = c
+                            .bus
+                            .handle_mqtt(*dev, &msg, *tok)?
+                            .send(&mut self.mqtt, &c.tx)?;
+                        replies.extend(error_topics(&c.bus, r));
+                    }
+                    if let Some(retract) = c.bus.purge(topic, payload) {
+                        self.mqtt.send(retract)?;
+                    }
+                }
+                self.watch(replies);
+            }
+            MqttMsg::Reconnected => {
+                info!("Renewing MQTT subscriptions");
+                if let Some(topic) = &self.birth {
+                    self.mqtt.subscribe(topic)?;
+                }
+                for c in &self.ctrls {
+                    for msg in c.routes.subscriptions() {
+                        self.mqtt.send(msg)?;
+                    }
+                    if let Some(topic) = c.bus.purge_filter() {
+                        self.mqtt.send(MqttMsg::Sub { topic })?;
+                    }
+                }
+            }
+            _ => (), // ignore
+        }
+        Ok(())
+    }
+
     fn handle(&mut self) -> Result<()> {
-        // process first controller message separately to figure out controller number
-        let resp = self.ctrl_rx.recv().map_err(|_| Error::ChanClosed)??;
-        let contno = resp.contno;
-        let (mut mqtt, mqtt_chan) = MqttConnection::new(
-            &self.opt.mqtt_host,
-            &self.opt.mqtt_cred,
-            format!("ESERA/{}/status", contno),
-            None,
-        )?;
-        self.bus.handle_1wire(resp, &mut self.routes)?;
+        // Select borrows the receivers, so we keep cloned handles around
+        let ctrl_chans: Vec<_> = self.ctrls.iter().map(|c| c.rx.clone()).collect();
+        let mqtt_chan = self.mqtt_chan.clone();
         let mut sel = channel::Select::new();
+        // controllers are selected with indices 0..n, MQTT and command feedback come last
+        for rx in &ctrl_chans {
+            sel.recv(rx);
+        }
         let mqtt_idx = sel.recv(&mqtt_chan);
-        let ctrl_idx = sel.recv(&self.ctrl_rx);
+        let feedback = self.feedback.1.clone();
+        let feedback_idx = sel.recv(&feedback);
         loop {
             let op = sel.select();
             match op.index() {
-                i if i == ctrl_idx => {
-                    match op.recv(&self.ctrl_rx).map_err(|_| Error::ChanClosed)? {
-                        Ok(resp) => self
-                            .bus
-                            .handle_1wire(resp, &mut self.routes)?
-                            .send(&mut mqtt, &self.ctrl_tx)?,
-                        Err(ControllerError::Transport(e)) => {
-                            error!("[{}] No data received from controller ({})", contno, e);
-                            return Err(Error::ChanClosed.into());
-                        }
-                        Err(e) => warn!("[{}] Controller read: {}", contno, e),
-                    };
-                }
                 i if i == mqtt_idx => {
                     let msg = op.recv(&mqtt_chan).map_err(|_| Error::MqttClosed)?;
-                    match msg {
-                        MqttMsg::Pub { ref topic, .. } => {
-                            for (dev, tok) in self.routes.lookup(topic) {
-                                self.bus.devices[*dev]
-                                    .handle_mqtt(&msg, *tok)?
-                                    .send(&mut mqtt, &self.ctrl_tx)?
-                            }
-                        }
-                        MqttMsg::Reconnected => {
-                            info!("Renewing MQTT subscriptions");
-                            for msg in self.routes.subscriptions() {
-                                mqtt.send(msg)?;
-                            }
-                        }
-                        _ => (), // ignore
-                    }
+                    self.handle_mqtt(msg)?;
+                }
+                i if i == feedback_idx => {
+                    let msg = op.recv(&feedback).map_err(|_| Error::ChanClosed)?;
+                    self.mqtt.send(msg)?;
+                }
+                i => {
+                    let resp = op.recv(&ctrl_chans[i]).map_err(|_| Error::ChanClosed)?;
+                    self.handle_ctrl(i, resp)?;
                 }
-                _ => panic!("BUG: unknown select() channel indexed"),
             }
         }
     }
 }
 
 fn run(opt: Opt) -> Result<()> {
+    let conf = Arc::new(opt.config()?);
+    // all controller connections are driven by a common runtime
+    let rt = Runtime::new()?;
+    // Buses live as long as the process so that saved outputs are restored only once and not
+    // after each MQTT reconnect.
+    let mut buses: Vec<_> = conf
+        .controllers
+        .iter()
+        .map(|_| Bus::new(conf.clone()))
+        .collect();
     debug!("Entering main event loop");
     loop {
-        match App::new(&opt).and_then(|mut app| app.handle()) {
+        let res = match App::new(&conf, rt.handle(), &mut buses) {
+            Ok(mut app) => {
+                let res = app.handle();
+                buses = app.into_buses();
+                res
+            }
+            Err(e) => Err(e),
+        };
+        match res {
             Ok(_) => return Ok(()),
             Err(e) => error!("{}", e),
         }
-        warn!("Connection lost, retrying in 5s");
-        thread::sleep(Duration::new(5, 0));
+        warn!("Connection lost, retrying in {}s", conf.timing.reconnect);
+        thread::sleep(Duration::from_secs(conf.timing.reconnect));
     }
 }
 
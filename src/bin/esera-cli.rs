@@ -0,0 +1,376 @@
+#[macro_use]
+extern crate log;
+
+use anyhow::{bail, Context, Result};
+use chrono::Local;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use structopt::StructOpt;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::time::{sleep_until, Instant};
+
+use esera_mqtt::capture::Recorder;
+use esera_mqtt::inventory::{self, Inventory};
+use esera_mqtt::{
+    Bus, Command, Config, ControllerConnection, ControllerError, Msg, Reply, Routes, OW,
+};
+
+const DEFAULT_PORT: u16 = 5000;
+
+type Conn = ControllerConnection<OwnedReadHalf, OwnedWriteHalf>;
+
+#[derive(StructOpt, Debug)]
+enum Cmd {
+    /// Show controller information (GET,SYS,INFO)
+    Info,
+    /// List devices on the 1-Wire bus (GET,OWB,LISTALL1)
+    List,
+    /// Show controller settings (GET,SYS,SETTING)
+    Settings,
+    /// Query a value, e.g. `get SYS TIME`
+    Get {
+        #[structopt(value_name = "SECTION")]
+        section: String,
+        #[structopt(value_name = "KEY")]
+        key: String,
+        #[structopt(value_name = "ARGS")]
+        args: Vec<String>,
+    },
+    /// Change a value, e.g. `set OWD OUT 2 2 1`
+    Set {
+        #[structopt(value_name = "SECTION")]
+        section: String,
+        #[structopt(value_name = "KEY")]
+        key: String,
+        #[structopt(value_name = "ARGS")]
+        args: Vec<String>,
+    },
+    /// Send a raw command line, e.g. `send SET,SYS,KAL,1`
+    Send {
+        #[structopt(value_name = "LINE")]
+        line: String,
+    },
+    /// Print controller events as they arrive
+    Watch {
+        /// Stop after this many seconds
+        #[structopt(short = "t", long, value_name = "SECS")]
+        timeout: Option<f64>,
+    },
+    /// Execute commands from a file. Lines of the form `sleep <SECS>` pause execution, lines
+    /// starting with `#` are ignored.
+    Run {
+        #[structopt(value_name = "SCRIPT")]
+        script: PathBuf,
+    },
+    /// List devices together with their MQTT topics and discovery ids as the bridge would
+    /// publish them
+    Inventory {
+        /// Output CSV instead of text
+        #[structopt(long)]
+        csv: bool,
+        /// Take topic prefixes and discovery settings from this bridge config file
+        #[structopt(short, long, value_name = "PATH")]
+        config: Option<PathBuf>,
+    },
+}
+
+/// Talks to an ESERA controller
+#[derive(StructOpt, Debug)]
+struct Opt {
+    /// Controller address
+    #[structopt(value_name = "HOST|IP[:PORT]")]
+    controller: String,
+    /// Output JSON (one object per line) instead of text
+    #[structopt(short = "j", long)]
+    json: bool,
+    /// Record the session into a capture file
+    #[structopt(long, value_name = "PATH")]
+    record: Option<PathBuf>,
+    #[structopt(subcommand)]
+    cmd: Cmd,
+}
+
+/// Prints controller responses in the requested format.
+struct Output {
+    json: bool,
+}
+
+impl Output {
+    fn item(&self, item: Result<OW, ControllerError>) {
+        match item {
+            Ok(ow) => self.print(&ow),
+            Err(ControllerError::Parse(e)) => {
+                // first line is just the position
+                warn!("Unparseable: {}", e.lines().nth(1).unwrap_or(&e).trim())
+            }
+            Err(e) => error!("{}", e),
+        }
+    }
+
+    fn print(&self, ow: &OW) {
+        if self.json {
+            self.line(&serde_json::to_string(ow).expect("serializable"));
+        } else {
+            self.line(&text(ow));
+        }
+    }
+
+    fn line(&self, s: &str) {
+        if writeln!(io::stdout(), "{}", s).is_err() {
+            // reader has gone away (e.g. `| head`)
+            std::process::exit(0)
+        }
+    }
+}
+
+/// Human readable representation of a parsed message
+fn text(ow: &OW) -> String {
+    let head = format!("{} [{}]", Local::now().format("%H:%M:%S"), ow.contno);
+    match &ow.msg {
+        Msg::Devstatus(s) if s.bits => format!("{} {} = {:08b}", head, s.addr, s.val),
+        Msg::Devstatus(s) => format!("{} {} = {}", head, s.addr, s.val),
+        Msg::OWDStatus(s) => format!("{} OWD{} status {}", head, s.owd, s.status),
+        Msg::OWDName(n) => format!("{} OWD{} name {:?}", head, n.owd, n.name),
+        Msg::CSI(csi) => format!(
+            "{} {} serial {}, firmware {}, hardware {}, time {} {}",
+            head, csi.artno, csi.serno, csi.fw, csi.hw, csi.date, csi.time
+        ),
+        Msg::List3(l) => {
+            let mut s = format!("{} {} devices", head, l.len());
+            for dev in l {
+                s.push_str(&format!(
+                    "\n  {:<6} {:<16} {:<6} {:<12} {}",
+                    dev.busid,
+                    dev.serno,
+                    dev.artno,
+                    dev.status,
+                    dev.name.as_deref().unwrap_or("")
+                ))
+            }
+            s
+        }
+        Msg::Settings(settings) => {
+            let mut s = format!("{} settings", head);
+            if let serde_json::Value::Object(map) =
+                serde_json::to_value(settings).expect("serializable")
+            {
+                for (k, v) in map {
+                    s.push_str(&format!("\n  {:<14} {}", k, v))
+                }
+            }
+            s
+        }
+        Msg::ErrList(l) => {
+            let mut s = format!("{} 1-Wire errors", head);
+            for e in l {
+                s.push_str(&format!("\n  OWD{:<3} {}", e.owd, e.count))
+            }
+            s
+        }
+        other => format!("{} {:?}", head, other),
+    }
+}
+
+/// Commands whose outcome has not been checked yet
+type Posted = Vec<(Command, Reply)>;
+
+/// Sends a command without waiting for its outcome, so that a sequence of commands is not held
+/// up by the controller's error reporting delay. Everything received meanwhile is printed.
+async fn post(conn: &mut Conn, cmd: Command, posted: &mut Posted, out: &Output) -> Result<()> {
+    let reply = conn.post(cmd.clone()).await?;
+    posted.push((cmd, reply));
+    while let Some(item) = conn.queue.pop_front() {
+        out.item(item)
+    }
+    Ok(())
+}
+
+/// Waits for the outcome of all posted commands and prints everything received meanwhile,
+/// including responses.
+async fn settle(conn: &mut Conn, posted: &mut Posted, out: &Output) -> Result<()> {
+    let res = conn.settle().await;
+    while let Some(item) = conn.queue.pop_front() {
+        out.item(item)
+    }
+    res?;
+    for (cmd, mut reply) in posted.drain(..) {
+        match reply.try_recv() {
+            Ok(Ok(_)) => (),
+            Ok(Err(ControllerError::Controller(code))) => {
+                error!("Controller rejected {} (error {})", cmd, code)
+            }
+            Ok(Err(e @ ControllerError::Timeout(_))) => error!("{}", e),
+            Ok(Err(e)) => return Err(e.into()),
+            Err(_) => bail!("Outcome of {} is unknown", cmd),
+        }
+    }
+    Ok(())
+}
+
+/// Sends a single command and prints everything received until its outcome is known.
+async fn exchange(conn: &mut Conn, cmd: Command, out: &Output) -> Result<()> {
+    let mut posted = Posted::new();
+    post(conn, cmd, &mut posted, out).await?;
+    settle(conn, &mut posted, out).await
+}
+
+fn build(op: &str, section: &str, key: &str, args: &[String]) -> Result<Command> {
+    let mut line = format!("{},{},{}", op, section.to_uppercase(), key.to_uppercase());
+    for a in args {
+        line.push(',');
+        line.push_str(a);
+    }
+    parse(&line)
+}
+
+fn parse(line: &str) -> Result<Command> {
+    line.trim()
+        .parse()
+        .with_context(|| format!("Invalid command '{}'", line.trim()))
+}
+
+/// Converts a user-supplied number of seconds.
+fn duration(secs: f64) -> Result<Duration> {
+    match Duration::try_from_secs_f64(secs) {
+        Ok(d) => Ok(d),
+        Err(_) => bail!("Invalid number of seconds: {}", secs),
+    }
+}
+
+async fn watch(conn: &mut Conn, timeout: Option<Duration>, out: &Output) -> Result<()> {
+    let end = timeout.map(|t| Instant::now() + t);
+    loop {
+        tokio::select! {
+            item = conn.get() => match item {
+                Some(item) => out.item(item),
+                None => bail!("Controller closed the connection"),
+            },
+            _ = sleep_until(end.unwrap_or_else(Instant::now)), if end.is_some() => return Ok(()),
+        }
+    }
+}
+
+async fn run_script(conn: &mut Conn, script: &str, out: &Output) -> Result<()> {
+    let mut posted = Posted::new();
+    for (n, line) in script.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut words = line.split_whitespace();
+        if words.next() == Some("sleep") {
+            let secs = words
+                .next()
+                .unwrap_or_default()
+                .parse()
+                .map_err(anyhow::Error::from)
+                .and_then(duration)
+                .with_context(|| format!("Line {}: invalid sleep time", n + 1))?;
+            settle(conn, &mut posted, out).await?;
+            // keep printing whatever arrives meanwhile
+            watch(conn, Some(secs), out).await?;
+            continue;
+        }
+        let cmd = parse(line).with_context(|| format!("Line {}", n + 1))?;
+        if !out.json {
+            out.line(&format!(">>> {}", cmd));
+        }
+        post(conn, cmd, &mut posted, out).await?;
+    }
+    settle(conn, &mut posted, out).await
+}
+
+/// Feeds controller information and device list into a bus model, just like the bridge does.
+async fn inventory(conn: &mut Conn, conf: Config) -> Result<Inventory> {
+    let mut bus = Bus::new(Arc::new(conf));
+    let mut routes = Routes::new();
+    bus.handle_1wire(conn.csi().await?, &mut routes)?;
+    bus.handle_1wire(conn.list().await?, &mut routes)?;
+    Ok(bus.inventory())
+}
+
+fn print_inventory(inv: &[inventory::Item], csv: bool, out: &Output) {
+    if csv {
+        // to_csv terminates each line
+        out.line(inventory::to_csv(inv).trim_end());
+    } else if out.json {
+        out.line(&serde_json::to_string(inv).expect("serializable"));
+    } else {
+        for item in inv {
+            out.line(&format!(
+                "{:<6} {:<16} {:<6} {:<12} {:<8} {}",
+                item.busid,
+                item.serno,
+                item.artno,
+                item.model,
+                item.status,
+                item.name.as_deref().unwrap_or("")
+            ));
+            for topic in &item.topics {
+                out.line(&format!("  {}", topic))
+            }
+            for id in &item.discovery {
+                out.line(&format!("  [discovery] {}", id))
+            }
+        }
+    }
+}
+
+async fn run(opt: Opt) -> Result<()> {
+    let out = Output { json: opt.json };
+    let recorder = match &opt.record {
+        Some(path) => Some(
+            Recorder::create(path, &opt.controller)
+                .with_context(|| format!("Cannot record to {}", path.display()))?,
+        ),
+        None => None,
+    };
+    let mut conn = if opt.controller.contains(':') {
+        ControllerConnection::open(opt.controller.as_str(), recorder).await
+    } else {
+        ControllerConnection::open((opt.controller.as_str(), DEFAULT_PORT), recorder).await
+    }
+    .with_context(|| format!("Cannot connect to {}", opt.controller))?;
+    match opt.cmd {
+        Cmd::Info => out.print(&conn.csi().await?),
+        Cmd::List => out.print(&conn.list().await?),
+        Cmd::Settings => out.print(&conn.settings().await?),
+        Cmd::Get { section, key, args } => {
+            exchange(&mut conn, build("GET", &section, &key, &args)?, &out).await?
+        }
+        Cmd::Set { section, key, args } => {
+            exchange(&mut conn, build("SET", &section, &key, &args)?, &out).await?
+        }
+        Cmd::Send { line } => exchange(&mut conn, parse(&line)?, &out).await?,
+        Cmd::Watch { timeout } => {
+            watch(&mut conn, timeout.map(duration).transpose()?, &out).await?
+        }
+        Cmd::Run { script } => {
+            let script = fs::read_to_string(&script)
+                .with_context(|| format!("Cannot read script {}", script.display()))?;
+            run_script(&mut conn, &script, &out).await?
+        }
+        Cmd::Inventory { csv, config } => {
+            let conf = match config {
+                Some(path) => Config::read(path)?,
+                None => Config::default(),
+            };
+            print_inventory(&inventory(&mut conn, conf).await?, csv, &out)
+        }
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn"))
+        .format_timestamp(None)
+        .init();
+    if let Err(e) = run(Opt::from_args()).await {
+        error!("FATAL: {:#}", e);
+        std::process::exit(1)
+    }
+}
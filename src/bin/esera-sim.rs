@@ -0,0 +1,101 @@
+#[macro_use]
+extern crate log;
+
+use anyhow::{bail, Context, Result};
+use parking_lot::Mutex;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use structopt::StructOpt;
+use tokio::net::TcpListener;
+
+use esera_mqtt::sim::{self, SimDevice, Simulator};
+
+/// Device specification: `<OWD>=<KIND>[:<NAME>]`, e.g. `2=switch8:K1`
+#[derive(Debug, Clone)]
+struct DevSpec {
+    owd: u8,
+    name: String,
+    dev: SimDevice,
+}
+
+impl FromStr for DevSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (owd, kind) = s
+            .split_once('=')
+            .context("expected <OWD>=<KIND>[:<NAME>]")?;
+        let (kind, name) = kind.split_once(':').unwrap_or((kind, ""));
+        let owd: u8 = owd.parse().context("invalid OWD number")?;
+        if !(1..=30).contains(&owd) {
+            bail!("OWD number must be in range 1..30");
+        }
+        let dev = match kind {
+            "temphum" => SimDevice::temphum(),
+            "switch8" => SimDevice::switch8(),
+            "dimmer" => SimDevice::dimmer(),
+            "shutter" => SimDevice::shutter(),
+            _ => bail!("unknown device kind '{}'", kind),
+        };
+        Ok(Self {
+            owd,
+            name: name.into(),
+            dev,
+        })
+    }
+}
+
+/// Emulates an ESERA controller with virtual 1-Wire devices
+#[derive(StructOpt, Debug)]
+struct Opt {
+    /// Address to listen on
+    #[structopt(short = "l", long, default_value = "127.0.0.1:5000")]
+    listen: String,
+    /// Controller number
+    #[structopt(short = "n", long, default_value = "1")]
+    contno: u8,
+    /// Virtual devices as <OWD>=<temphum|switch8|dimmer|shutter>[:<NAME>]. Without any, a sample
+    /// installation is simulated.
+    #[structopt(short = "d", long = "device", value_name = "SPEC")]
+    devices: Vec<DevSpec>,
+    /// Fault injection script
+    #[structopt(short = "s", long, value_name = "PATH")]
+    script: Option<PathBuf>,
+}
+
+async fn run(opt: Opt) -> Result<()> {
+    let sim = if opt.devices.is_empty() {
+        Simulator::demo(opt.contno)
+    } else {
+        opt.devices
+            .iter()
+            .cloned()
+            .fold(Simulator::new(opt.contno), |sim, d| {
+                sim.with_device(d.owd, &d.name, d.dev)
+            })
+    };
+    let script = match &opt.script {
+        Some(path) => sim::parse_script(
+            &fs::read_to_string(path)
+                .with_context(|| format!("Cannot read script {}", path.display()))?,
+        )?,
+        None => Vec::new(),
+    };
+    let listener = TcpListener::bind(&opt.listen)
+        .await
+        .with_context(|| format!("Cannot listen on {}", opt.listen))?;
+    info!("Simulating controller {} on {}", opt.contno, opt.listen);
+    sim::serve(listener, Arc::new(Mutex::new(sim)), script).await?;
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    env_logger::builder().format_timestamp(None).init();
+    if let Err(e) = run(Opt::from_args()).await {
+        error!("FATAL: {:#}", e);
+        std::process::exit(1)
+    }
+}
@@ -1,11 +1,28 @@
 use crate::device::*;
+use crate::inventory::{Inventory, Item};
+use crate::mqtt::Kind;
 use crate::parser::Msg;
-use crate::{parser, Device, DeviceInfo, MqttMsg, Routes, Status, TwoWay, CSI, OW};
+use crate::state::{Outputs, Restore, StateStore};
+use crate::{
+    parser, Command, Config, Device, DeviceInfo, MqttMsg, Prefix, Routes, Section, Status, Token,
+    TwoWay, CSI, OW,
+};
 
-use std::collections::HashMap;
+use serde_json::json;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use thiserror::Error;
 
+/// Minimum time between two event-triggered bus rescans
+const RESCAN_HOLDOFF: Duration = Duration::from_secs(30);
+
+/// Highest OWD number which can be addressed in controller commands
+const MAX_OWD: usize = u8::MAX as usize;
+/// Routing token for `<dev>/name/set`, handled by the bus itself
+const RENAME: Token = -1;
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error(transparent)]
@@ -18,20 +35,84 @@ pub enum Error {
 
 type Result<T, E = Error> = std::result::Result<T, E>;
 
-#[derive(Debug, Clone, Default, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Bus {
     pub contno: u8,
-    pub devices: [Model; 31],
+    pub devices: Vec<Model>, // indexed by OWD number, 0 is the controller
     busaddrs: HashMap<String, usize>, // indexes into `devices`
+    conf: Arc<Config>,
+    prefix: Arc<Prefix>,
+    announced: HashSet<String>,       // discovery topics
+    states: HashMap<String, MqttMsg>, // last state message per topic
+    restore: Vec<(usize, Command)>,   // commands to be issued after a controller reset
+    store: StateStore,                // commanded outputs
+    startup: bool,                    // state file has been loaded, but not applied yet
+    scanned: bool,                    // device list has been loaded at least once
+    reinit: bool,                     // controller (re)connected or reset: initialize all slots
+    changed: HashSet<usize>,          // slots with new occupants since the last device list
+    last_scan: Option<Instant>,       // time of last device list
+    unknown: HashSet<String>,         // unregistered busaddrs which have triggered a rescan
+    errors: HashMap<usize, u32>,      // 1-Wire error counters per slot (0: whole bus)
+}
+
+/// Collects all `*topic` fields of a discovery announcement.
+fn announced_topics(ann: &serde_json::Value, topics: &mut BTreeSet<String>) {
+    match ann {
+        serde_json::Value::Object(fields) => {
+            for (key, val) in fields {
+                match val {
+                    serde_json::Value::String(t) if key.ends_with("topic") => {
+                        topics.insert(t.clone());
+                    }
+                    _ => announced_topics(val, topics),
+                }
+            }
+        }
+        serde_json::Value::Array(items) => {
+            for item in items {
+                announced_topics(item, topics)
+            }
+        }
+        _ => (),
+    }
+}
+
+/// Whether a device list entry denotes an actual device (empty slots have all-F serials)
+fn present(info: &DeviceInfo) -> bool {
+    !info.serno.is_empty() && info.serno.bytes().any(|b| b != b'F')
 }
 
 impl Bus {
+    pub fn new(conf: Arc<Config>) -> Self {
+        Self {
+            contno: 0,
+            devices: vec![Model::default()],
+            busaddrs: HashMap::new(),
+            prefix: Arc::new(conf.prefix()),
+            conf,
+            announced: HashSet::new(),
+            states: HashMap::new(),
+            restore: Vec::new(),
+            store: StateStore::default(),
+            startup: false,
+            scanned: false,
+            reinit: false,
+            changed: HashSet::new(),
+            last_scan: None,
+            unknown: HashSet::new(),
+            errors: HashMap::new(),
+        }
+    }
+
     /// Updates busaddr to device mapping.
     fn register_1wire(&mut self) {
+        self.busaddrs.clear();
         for (i, dev) in self.devices.iter().enumerate() {
             self.busaddrs
                 .extend(dev.register_1wire().into_iter().map(|a| (a, i)))
         }
+        let busaddrs = &self.busaddrs;
+        self.unknown.retain(|a| !busaddrs.contains_key(a));
         debug!("[{}] 1-Wire Registry: {:?}", self.contno, self.busaddrs);
     }
 
@@ -50,35 +131,282 @@ impl Bus {
                 .into_iter()
                 .filter_map(|(topic, tok)| routes.register(topic, i, tok))
                 .for_each(|msg| res += TwoWay::from_mqtt(msg));
+            if i > 0 {
+                if let Some(msg) = routes.register(dev.info().topic("name/set"), i, RENAME) {
+                    res += TwoWay::from_mqtt(msg);
+                }
+            }
         }
         debug!("MQTT registry: {:?}", routes);
         res
     }
 
-    /// Performs device-specific initialization commands for all configured devices.
-    fn init(&mut self) -> Vec<String> {
+    /// Performs device-specific initialization commands for configured devices in selected slots.
+    fn init<F: Fn(usize) -> bool>(&mut self, select: F) -> Vec<(usize, Command)> {
         self.devices
             .iter_mut()
-            .filter(|m| m.configured())
-            .flat_map(|d| d.init())
+            .enumerate()
+            .filter(|(i, m)| m.configured() && select(*i))
+            .flat_map(|(i, d)| d.init().into_iter().map(move |c| (i, c)))
             .collect()
     }
 
-    fn populate(&mut self, lst: parser::List3) {
+    /// Restore policy for a device. Device-specific settings override the global one.
+    fn policy(&self, dev: &Model) -> Restore {
+        self.conf
+            .device(dev.info().contno, dev.name())
+            .and_then(|c| c.restore)
+            .unwrap_or(self.conf.restore)
+    }
+
+    /// Collects commands which set outputs according to each device's restore policy. After a
+    /// controller reset, the last known outputs are used. On startup, only saved states are
+    /// available.
+    fn restore_cmds(&self, startup: bool) -> Vec<(usize, Command)> {
+        let mut cmds = Vec::new();
+        for (i, dev) in self
+            .devices
+            .iter()
+            .enumerate()
+            .filter(|(_, m)| m.configured())
+        {
+            let saved = self.store.get(&dev.info().serno);
+            let issued = |c| (i, c);
+            match self.policy(dev) {
+                Restore::Off if !startup => {
+                    cmds.extend(dev.restore(&Outputs::new()).into_iter().map(issued))
+                }
+                Restore::Last => {
+                    let current = dev.outputs();
+                    let outputs = if startup || current.is_empty() {
+                        saved
+                    } else {
+                        Some(&current)
+                    };
+                    if let Some(o) = outputs {
+                        cmds.extend(dev.restore(o).into_iter().map(issued))
+                    }
+                }
+                _ => (),
+            }
+        }
+        cmds
+    }
+
+    /// Topic for bus change events (devices added, removed or replaced)
+    pub fn event_topic(&self) -> String {
+        format!("{}/{}/bus", self.prefix.base, self.contno)
+    }
+
+    /// Retained controller settings (JSON)
+    pub fn settings_topic(&self) -> String {
+        format!("{}/{}/settings", self.prefix.base, self.contno)
+    }
+
+    /// Retained inventory of all devices (JSON)
+    pub fn inventory_topic(&self) -> String {
+        format!("{}/{}/inventory", self.prefix.base, self.contno)
+    }
+
+    /// Describes all configured devices along with their MQTT topics and discovery entries.
+    pub fn inventory(&self) -> Inventory {
+        let disc_prefix = format!("{}/", self.prefix.discovery);
+        self.devices
+            .iter()
+            .enumerate()
+            .filter(|(_, d)| d.configured())
+            .map(|(i, dev)| {
+                let info = dev.info();
+                let base = info.topic("");
+                let mut topics: BTreeSet<String> =
+                    dev.register_mqtt().into_iter().map(|(t, _)| t).collect();
+                if i > 0 {
+                    topics.insert(info.topic("name/set"));
+                }
+                topics.insert(info.status_topic());
+                let mut ann = dev.announce();
+                ann.push(dev.announce_errors());
+                let mut announced = BTreeSet::new();
+                for m in &ann {
+                    if let Ok(payload) = serde_json::from_str(m.payload()) {
+                        announced_topics(&payload, &mut announced);
+                    }
+                }
+                topics.extend(announced.into_iter().filter(|t| t.starts_with(&base)));
+                let discovery = if self.conf.discovery.enabled {
+                    ann.iter()
+                        .filter_map(|m| {
+                            let id = m.topic().strip_prefix(&disc_prefix)?;
+                            id.strip_suffix("/config").map(String::from)
+                        })
+                        .collect()
+                } else {
+                    Vec::new()
+                };
+                Item {
+                    contno: info.contno,
+                    busid: info.busid.clone(),
+                    serno: info.serno.clone(),
+                    artno: info.artno.clone(),
+                    model: dev.model().into(),
+                    name: info.name.clone(),
+                    status: info.status,
+                    topics: topics.into_iter().collect(),
+                    discovery,
+                }
+            })
+            .collect()
+    }
+
+    fn inventory_msg(&self) -> MqttMsg {
+        MqttMsg::retain(
+            self.inventory_topic(),
+            serde_json::to_string(&self.inventory()).expect("serializable"),
+        )
+    }
+
+    fn bus_event(&self, event: &str, old: &DeviceInfo, new: &DeviceInfo) -> MqttMsg {
+        info!(
+            "[{}] Device {} {}: {} -> {}",
+            self.contno, new.busid, event, old.serno, new.serno
+        );
+        let dev = if event == "removed" { old } else { new };
+        let mut ev = json!({
+            "event": event,
+            "busid": dev.busid,
+            "serno": dev.serno,
+            "artno": dev.artno,
+            "name": dev.name(),
+        });
+        if event == "replaced" {
+            ev["old_serno"] = json!(old.serno);
+        }
+        if event == "renamed" {
+            ev["old_name"] = json!(old.name());
+        }
+        MqttMsg::new(self.event_topic(), ev.to_string())
+    }
+
+    /// Records 1-Wire error counters and publishes them for known devices. Slot 0 holds the
+    /// controller's sum over the whole bus.
+    fn error_counts<I: IntoIterator<Item = (usize, u32)>>(&mut self, counts: I) -> TwoWay {
+        let mut msgs = Vec::new();
+        for (n, count) in counts {
+            let prev = self.errors.insert(n, count);
+            let dev = match self.devices.get(n) {
+                Some(dev) if dev.configured() => dev,
+                _ => continue,
+            };
+            match prev {
+                Some(prev) if count > prev => warn!(
+                    "[{}] {}: {} new 1-Wire error(s), {} total",
+                    self.contno,
+                    dev.name(),
+                    count - prev,
+                    count
+                ),
+                _ => (),
+            }
+            msgs.push(dev.info().mqtt_msg("errors", count));
+        }
+        TwoWay::mqtt(msgs)
+    }
+
+    /// Requests a new device list unless one has been received recently.
+    fn rescan(&mut self, reason: fmt::Arguments) -> TwoWay {
+        match self.last_scan {
+            Some(t) if t.elapsed() < RESCAN_HOLDOFF => TwoWay::default(),
+            _ => {
+                info!("[{}] Rescanning bus: {}", self.contno, reason);
+                self.last_scan = Some(Instant::now());
+                TwoWay::from_1wire(Command::get(Section::OWB, "LISTALL1"))
+            }
+        }
+    }
+
+    /// Loads device list and compares it with the previous one. Returns availability messages for
+    /// all devices and events for devices which have been added, removed or replaced.
+    fn populate(&mut self, lst: parser::List3) -> Vec<MqttMsg> {
         debug!("[{}] Loading device list", self.contno);
-        for (i, dev) in lst.into_iter().enumerate().take(30) {
+        self.last_scan = Some(Instant::now());
+        // no events for the initial device list
+        let initial = !std::mem::replace(&mut self.scanned, true);
+        let mut avail = Vec::with_capacity(lst.len());
+        let mut listed = HashSet::new();
+        for dev in lst {
             // devices[0] is reserved for the controller
-            let slot = &mut self.devices[i + 1];
-            let status = dev.status;
-            if slot.info().serno != dev.serno {
-                *slot = Model::select(dev);
+            let n = match dev.devno().parse::<usize>() {
+                Ok(n) if (1..=MAX_OWD).contains(&n) => n,
+                _ => {
+                    warn!("[{}] Ignoring invalid bus id {}", self.contno, dev.busid);
+                    continue;
+                }
+            };
+            if n >= self.devices.len() {
+                self.devices.resize_with(n + 1, Model::default);
             }
-            if slot.configured() {
-                slot.info_mut().status = status;
+            listed.insert(n);
+            avail.extend(self.update_slot(n, dev, initial));
+        }
+        // devices which are missing from the list altogether
+        for n in 1..self.devices.len() {
+            if !listed.contains(&n) && present(self.devices[n].info()) {
+                let gone = DeviceInfo {
+                    contno: self.contno,
+                    busid: format!("OWD{}", n),
+                    ..DeviceInfo::default()
+                };
+                avail.extend(self.update_slot(n, gone, initial));
             }
         }
         info!("{}", self);
         self.register_1wire();
+        avail
+    }
+
+    /// Puts a device list entry into slot `n`. Returns availability messages and bus events.
+    fn update_slot(&mut self, n: usize, mut dev: DeviceInfo, initial: bool) -> Vec<MqttMsg> {
+        let mut res = Vec::new();
+        let status = dev.status;
+        let old = self.devices[n].info();
+        if old.serno != dev.serno || old.name != dev.name {
+            let renamed = old.serno == dev.serno;
+            match (present(old) && !initial, present(&dev) && !initial) {
+                (true, true) if renamed => res.push(self.bus_event("renamed", old, &dev)),
+                (false, true) => res.push(self.bus_event("added", old, &dev)),
+                (true, false) => res.push(self.bus_event("removed", old, &dev)),
+                (true, true) => res.push(self.bus_event("replaced", old, &dev)),
+                (false, false) => (),
+            }
+            if !renamed {
+                // counters of the previous occupant are meaningless for the new one
+                self.errors.remove(&n);
+            }
+            let slot = &mut self.devices[n];
+            if slot.configured() {
+                // device has been removed, replaced or renamed
+                let base = slot.info().topic("");
+                self.states.retain(|topic, _| !topic.starts_with(&base));
+                if renamed {
+                    // nobody is going to look there anymore
+                    res.push(MqttMsg::retain(slot.info().status_topic(), ""));
+                } else {
+                    res.push(MqttMsg::retain(slot.info().status_topic(), Status::Offline));
+                }
+            }
+            dev.prefix = self.prefix.clone();
+            *slot = Model::select(dev);
+            self.changed.insert(n);
+            if let Some(conf) = self.conf.device(self.contno, slot.name()) {
+                slot.configure(conf);
+            }
+        }
+        let slot = &mut self.devices[n];
+        if slot.configured() {
+            slot.info_mut().status = status;
+            res.push(slot.info().status_msg());
+        }
+        res
     }
 
     pub fn set_controller(&mut self, contno: u8, csi: CSI) -> Result<TwoWay> {
@@ -86,8 +414,20 @@ impl Bus {
             "[{}] Controller {} S/N {} FW {}",
             contno, csi.artno, csi.serno, csi.fw
         );
+        if let Some(dir) = &self.conf.state_dir {
+            if !self.store.persistent() || self.contno != contno {
+                match StateStore::open(dir.join(format!("{}.json", contno))) {
+                    Ok(store) => {
+                        self.store = store;
+                        self.startup = true;
+                    }
+                    Err(e) => error!("[{}] {}", contno, e),
+                }
+            }
+        }
         // initialize bus entry so that we know this item is occupied
         self.contno = contno;
+        self.reinit = true;
         let slot = &mut self.devices[0];
         *slot = Model::select(DeviceInfo {
             contno,
@@ -96,22 +436,96 @@ impl Bus {
             status: Status::Online,
             artno: csi.artno.clone(),
             name: None,
+            prefix: self.prefix.clone(),
         });
+        if let Some(conf) = self.conf.device(contno, slot.name()) {
+            slot.configure(conf);
+        }
+        let online = vec![
+            MqttMsg::retain(slot.info().ctrl_status(), "online"),
+            slot.info().status_msg(),
+        ];
         // push down to actual device handler
         // this allows for additional initialization actions there
-        Ok(slot.handle_1wire(OW {
-            contno,
-            msg: Msg::CSI(csi),
-        })?)
+        Ok(TwoWay::mqtt(online)
+            + slot.handle_1wire(OW {
+                contno,
+                msg: Msg::CSI(csi),
+            })?)
     }
 
-    /// Collects device discovery messages from all devices.
-    fn announce(&self) -> Vec<MqttMsg> {
-        self.devices
+    /// Collects device discovery messages from devices in selected slots. Announcements which
+    /// have been sent previously but are not valid anymore (e.g., after a device has been
+    /// replaced) are retracted.
+    fn announce<F: Fn(usize) -> bool>(&mut self, select: F) -> Vec<MqttMsg> {
+        if !self.conf.discovery.enabled {
+            return Vec::new();
+        }
+        let all: Vec<(usize, MqttMsg)> = self
+            .devices
             .iter()
-            .filter(|m| m.configured())
-            .flat_map(|d| d.announce())
-            .collect()
+            .enumerate()
+            .filter(|(_, m)| m.configured())
+            .flat_map(|(i, d)| {
+                let mut msgs = d.announce();
+                msgs.push(d.announce_errors());
+                msgs.into_iter().map(move |m| (i, m))
+            })
+            .collect();
+        let announced: HashSet<String> = all
+            .iter()
+            .filter_map(|(_, m)| match m {
+                MqttMsg::Pub { topic, .. } => Some(topic.clone()),
+                _ => None,
+            })
+            .collect();
+        let mut msgs: Vec<MqttMsg> = all
+            .into_iter()
+            .filter(|(i, _)| select(*i))
+            .map(|(_, m)| m)
+            .collect();
+        for stale in self.announced.difference(&announced) {
+            info!("[{}] Removing discovery entry {}", self.contno, stale);
+            msgs.push(MqttMsg::retract(stale.as_str()));
+        }
+        self.announced = announced;
+        msgs
+    }
+
+    /// Topic filter which matches all discovery announcements belonging to this controller.
+    /// Returns `None` unless purging is enabled.
+    pub fn purge_filter(&self) -> Option<String> {
+        if self.conf.discovery.purge && self.contno > 0 {
+            Some(format!(
+                "{}/+/{}/+/config",
+                self.prefix.discovery, self.contno
+            ))
+        } else {
+            None
+        }
+    }
+
+    /// Checks retained discovery announcements found on the broker. Returns a retraction if the
+    /// announcement belongs to this controller, but not to any current device.
+    pub fn purge(&self, topic: &str, payload: &str) -> Option<MqttMsg> {
+        if payload.is_empty() || self.announced.contains(topic) || self.purge_filter().is_none() {
+            return None;
+        }
+        let parts: Vec<_> = topic
+            .strip_prefix(&self.prefix.discovery)?
+            .strip_prefix('/')?
+            .split('/')
+            .collect();
+        match parts.as_slice() {
+            [_typ, contno, _id, "config"] if *contno == self.contno.to_string() => {
+                info!(
+                    "[{}] Purging unknown discovery entry {}",
+                    self.contno, topic
+                );
+                Some(MqttMsg::retract(topic))
+            }
+            _ => None,
+        }
     }
 
     /// Find index of registered busaddr (if any)
@@ -119,28 +533,200 @@ impl Bus {
         self.busaddrs.get(busaddr).copied()
     }
 
+    /// Whether a busaddr refers to a 1-Wire slot which holds no known device. Unregistered
+    /// sub-addresses of known devices (like bitstring forms) are expected.
+    fn vacant(&self, busaddr: &str) -> bool {
+        let owd = busaddr
+            .strip_prefix("OWD")
+            .and_then(|a| a.split('_').next())
+            .and_then(|n| n.parse::<usize>().ok());
+        match owd {
+            Some(n) => !self.devices.get(n).is_some_and(|d| d.configured()),
+            None => false,
+        }
+    }
+
+    /// Announces all devices again and republishes their last known states. Used when Home
+    /// Assistant comes back online.
+    pub fn republish(&mut self) -> Vec<MqttMsg> {
+        let mut res = self.announce(|_| true);
+        res.extend(self.states.values().cloned());
+        res
+    }
+
+    /// Passes an MQTT command to a device and records the resulting outputs.
+    pub fn handle_mqtt(&mut self, dev: usize, msg: &MqttMsg, token: Token) -> Result<TwoWay> {
+        if token == RENAME {
+            return Ok(self.rename(dev, msg));
+        }
+        let n = dev;
+        let dev = &mut self.devices[n];
+        let res = dev.handle_mqtt(msg, token)?;
+        if let Err(e) = self.store.update(&dev.info().serno, dev.outputs()) {
+            error!("[{}] {}", self.contno, e);
+        }
+        Ok(res.issued_by(n))
+    }
+
+    /// Assigns a new name to the device in slot `n` and reloads the device list. The retained
+    /// request is cleared so that it does not apply to later occupants of the same slot.
+    fn rename(&mut self, n: usize, msg: &MqttMsg) -> TwoWay {
+        let name = msg.payload().trim();
+        if name.is_empty() {
+            // our own cleanup
+            return TwoWay::default();
+        }
+        let mut res = TwoWay::from_mqtt(MqttMsg::retain(msg.topic(), ""));
+        let info = self.devices[n].info();
+        if info.name() == name {
+            return res;
+        }
+        let cmd = Command::Name {
+            devno: info.owd(),
+            name: name.into(),
+        };
+        if let Err(e) = cmd.validate() {
+            error!("[{}] {}: invalid name: {}", self.contno, info.name(), e);
+            return res;
+        }
+        // topics are derived from names, so they must be unique. Names of the OWD<n> form would
+        // clash with unnamed devices in other slots.
+        let taken = self
+            .devices
+            .iter()
+            .enumerate()
+            .any(|(i, d)| i != n && (d.info().name() == name || d.info().busid == name));
+        let busid_like = name != info.busid
+            && name
+                .strip_prefix("OWD")
+                .is_some_and(|d| d.parse::<usize>().is_ok());
+        if taken || busid_like {
+            error!(
+                "[{}] {}: name {} is already in use",
+                self.contno,
+                info.name(),
+                name
+            );
+            return res;
+        }
+        info!("[{}] Renaming {} to {}", self.contno, info.name(), name);
+        self.last_scan = Some(Instant::now());
+        res.ow = vec![(n, cmd), (0, Command::get(Section::OWB, "LISTALL1"))];
+        res
+    }
+
     /// Main processing entry point for incoming 1-Wire events.
     pub fn handle_1wire(&mut self, resp: OW, routes: &mut Routes<usize>) -> Result<TwoWay> {
+        let res = self.dispatch(resp, routes)?;
+        for msg in &res.mqtt {
+            match msg {
+                // retractions are not worth repeating
+                MqttMsg::Pub {
+                    topic,
+                    payload,
+                    kind: Kind::State,
+                    ..
+                } if !payload.is_empty() => {
+                    self.states.insert(topic.clone(), msg.clone());
+                }
+                _ => (),
+            }
+        }
+        Ok(res)
+    }
+
+    fn dispatch(&mut self, resp: OW, routes: &mut Routes<usize>) -> Result<TwoWay> {
         let contno = resp.contno;
         match resp.msg {
             Msg::CSI(csi) => return self.set_controller(contno, csi),
             Msg::List3(l) => {
-                self.populate(l);
-                let res = self.register_mqtt(routes);
-                let init_cmds = self.init();
-                let discovery_ann = self.announce();
-                return Ok(res + TwoWay::new(discovery_ann, init_cmds));
+                let avail = self.populate(l);
+                // periodic lists must not disturb devices which are already set up
+                let all = std::mem::take(&mut self.reinit);
+                let changed = std::mem::take(&mut self.changed);
+                let select = |i| all || changed.contains(&i);
+                let mut res = TwoWay::default();
+                let mut init_cmds = Vec::new();
+                let mut discovery_ann = Vec::new();
+                if all || !changed.is_empty() {
+                    res = self.register_mqtt(routes);
+                    init_cmds = self.init(select);
+                    discovery_ann = self.announce(select);
+                    if let Some(topic) = self.purge_filter() {
+                        discovery_ann.push(MqttMsg::Sub { topic })
+                    }
+                }
+                if self.startup {
+                    init_cmds.extend(self.restore_cmds(true));
+                    self.startup = false;
+                }
+                init_cmds.append(&mut self.restore);
+                return Ok(res
+                    + TwoWay {
+                        mqtt: discovery_ann,
+                        ow: init_cmds,
+                    }
+                    + TwoWay::mqtt(avail)
+                    + TwoWay::from_mqtt(self.inventory_msg()));
             }
             Msg::DIO(_) => return Ok(self.devices[0].handle_1wire(resp)?),
             Msg::Devstatus(ref s) => {
                 debug!("[{}] {:?}", contno, resp.msg);
                 if let Some(i) = self.index(&s.addr) {
-                    return Ok(self.devices[i].handle_1wire(resp)?);
+                    return Ok(self.devices[i].handle_1wire(resp)?.issued_by(i));
+                }
+                if self.scanned && self.vacant(&s.addr) && self.unknown.insert(s.addr.clone()) {
+                    return Ok(self.rescan(format_args!("unknown busaddr {}", s.addr)));
                 }
             }
-            Msg::OWDStatus(ref s) => {
-                debug!("[{}] {:?}", contno, resp.msg);
-                return Ok(self.devices[s.owd as usize].handle_1wire(resp)?);
+            Msg::OWDStatus(s) => {
+                debug!("[{}] OWD{} status: {}", contno, s.owd, s.status);
+                let mut res = TwoWay::default();
+                let mut changed = true;
+                match self.devices.get_mut(s.owd as usize) {
+                    Some(dev) if dev.configured() => {
+                        changed = dev.info().status != s.status;
+                        dev.info_mut().status = s.status;
+                        res += TwoWay::from_mqtt(dev.info().status_msg());
+                    }
+                    _ => warn!("[{}] Status change for unknown OWD{}", contno, s.owd),
+                }
+                if changed {
+                    res += TwoWay::from_mqtt(self.inventory_msg());
+                    res += self.rescan(format_args!("OWD{} status change", s.owd));
+                }
+                return Ok(res);
+            }
+            Msg::Rst(_) | Msg::Rdy(_) => {
+                warn!("[{}] Controller reset", contno);
+                self.reinit = true;
+                if self.restore.is_empty() {
+                    // outputs will be reported as off soon, so take a snapshot now
+                    self.restore = self.restore_cmds(false);
+                }
+            }
+            Msg::Settings(s) => {
+                debug!("[{}] {:?}", contno, s);
+                return Ok(TwoWay::from_mqtt(MqttMsg::retain(
+                    self.settings_topic(),
+                    serde_json::to_string(&s).expect("serializable"),
+                )));
+            }
+            Msg::ErrSum(n) => return Ok(self.error_counts(vec![(0, n)])),
+            Msg::ErrOwd(e) => return Ok(self.error_counts(vec![(e.owd as usize, e.count)])),
+            Msg::ErrList(l) => {
+                return Ok(self.error_counts(l.into_iter().map(|e| (e.owd as usize, e.count))))
+            }
+            Msg::OWDName(n) => {
+                debug!("[{}] OWD{} name: {:?}", contno, n.owd, n.name);
+                let known = self
+                    .devices
+                    .get(n.owd as usize)
+                    .and_then(|dev| dev.info().name.as_deref())
+                    .unwrap_or("");
+                if known != n.name {
+                    return Ok(self.rescan(format_args!("OWD{} renamed", n.owd)));
+                }
             }
             Msg::Keepalive(_) => (),
             Msg::Evt(_) => (),
@@ -161,3 +747,587 @@ impl fmt::Display for Bus {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn feed(bus: &mut Bus, routes: &mut Routes<usize>, input: &str) -> TwoWay {
+        bus.handle_1wire(parser::parse(input).unwrap().1, routes)
+            .unwrap()
+    }
+
+    fn cmds(res: &TwoWay) -> Vec<String> {
+        res.ow.iter().map(|(_, c)| c.to_string()).collect()
+    }
+
+    fn retained(res: &TwoWay, topic: &str) -> Option<String> {
+        res.mqtt.iter().find_map(|m| match m {
+            MqttMsg::Pub {
+                topic: t,
+                payload,
+                retain: true,
+                ..
+            } if t == topic => Some(payload.clone()),
+            _ => None,
+        })
+    }
+
+    /// Bus attached to controller 1 which has loaded `list`, if not empty
+    fn bus_with(conf: Config, list: &str) -> (Bus, Routes<usize>) {
+        let mut bus = Bus::new(Arc::new(conf));
+        let csi = CSI {
+            artno: "11340".into(),
+            serno: "1234".into(),
+            ..CSI::default()
+        };
+        bus.set_controller(1, csi).unwrap();
+        let mut routes = Routes::new();
+        if !list.is_empty() {
+            feed(&mut bus, &mut routes, list);
+        }
+        (bus, routes)
+    }
+
+    #[test]
+    fn device_availability() {
+        let (mut bus, mut routes) = bus_with(Config::default(), "");
+        let res = feed(
+            &mut bus,
+            &mut routes,
+            "1_LST3|00:02:54\n\
+             LST|1_OWD1|EF000019096A4026|S_0|11150\n\
+             LST|1_OWD2|4300001982956429|S_0|11220|K \n\
+             1_EVT|0:02:55\n",
+        );
+        assert_eq!(retained(&res, "ESERA/1/OWD1/status").unwrap(), "online");
+        assert_eq!(retained(&res, "ESERA/1/K/status").unwrap(), "online");
+        let res = feed(&mut bus, &mut routes, "1_OWD_2|5\n");
+        assert_eq!(retained(&res, "ESERA/1/K/status").unwrap(), "offline");
+        assert_eq!(bus.devices[2].info().status, Status::Offline);
+        // replaced device
+        let res = feed(
+            &mut bus,
+            &mut routes,
+            "1_LST3|00:03:54\n\
+             LST|1_OWD1|EF000019096A4026|S_0|11150\n\
+             LST|1_OWD2|4300001982956430|S_0|11220|L \n\
+             1_EVT|0:03:55\n",
+        );
+        assert_eq!(retained(&res, "ESERA/1/K/status").unwrap(), "offline");
+        assert_eq!(retained(&res, "ESERA/1/L/status").unwrap(), "online");
+    }
+
+    fn events(res: &TwoWay) -> Vec<serde_json::Value> {
+        res.mqtt
+            .iter()
+            .filter_map(|m| match m {
+                MqttMsg::Pub { topic, payload, .. } if topic == "ESERA/1/bus" => {
+                    Some(serde_json::from_str(payload).unwrap())
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rescan_on_bus_changes() {
+        let (mut bus, mut routes) = bus_with(Config::default(), "");
+        let res = feed(
+            &mut bus,
+            &mut routes,
+            "1_LST3|00:02:54\n\
+             LST|1_OWD1|EF000019096A4026|S_0|11150\n\
+             LST|1_OWD2|4300001982956429|S_0|11220|K \n\
+             LST|1_OWD3|FFFFFFFFFFFFFFFF|S_10|none\n\
+             1_EVT|0:02:55\n",
+        );
+        assert!(events(&res).is_empty());
+        // list has just been loaded
+        assert!(feed(&mut bus, &mut routes, "1_OWD3_1|3\n").ow.is_empty());
+        bus.last_scan = None;
+        assert!(cmds(&feed(&mut bus, &mut routes, "1_OWD4_1|3\n")) == ["GET,OWB,LISTALL1"]);
+        bus.last_scan = None;
+        // no repeated rescans for the same busaddr
+        assert!(feed(&mut bus, &mut routes, "1_OWD4_1|3\n").ow.is_empty());
+        // bitstring forms of known devices are not registered by default
+        assert!(feed(&mut bus, &mut routes, "1_OWD2_2|00000011\n")
+            .ow
+            .is_empty());
+        // unchanged status
+        assert!(feed(&mut bus, &mut routes, "1_OWD_2|0\n").ow.is_empty());
+        assert!(cmds(&feed(&mut bus, &mut routes, "1_OWD_2|5\n")) == ["GET,OWB,LISTALL1"]);
+        let res = feed(
+            &mut bus,
+            &mut routes,
+            "1_LST3|00:03:54\n\
+             LST|1_OWD1|FFFFFFFFFFFFFFFF|S_10|none\n\
+             LST|1_OWD2|4300001982956430|S_0|11220|K \n\
+             LST|1_OWD3|EF000019096A4027|S_0|11150\n\
+             1_EVT|0:03:55\n",
+        );
+        assert_eq!(
+            events(&res),
+            vec![
+                json!({"event": "removed", "busid": "OWD1", "serno": "EF000019096A4026",
+                       "artno": "11150", "name": "OWD1"}),
+                json!({"event": "replaced", "busid": "OWD2", "serno": "4300001982956430",
+                       "artno": "11220", "name": "K", "old_serno": "4300001982956429"}),
+                json!({"event": "added", "busid": "OWD3", "serno": "EF000019096A4027",
+                       "artno": "11150", "name": "OWD3"}),
+            ]
+        );
+        assert_eq!(bus.index("OWD1_1"), None);
+        assert_eq!(bus.index("OWD3_1"), Some(3));
+    }
+
+    #[test]
+    fn devices_indexed_by_owd_number() {
+        let (mut bus, mut routes) = bus_with(
+            Config::default(),
+            "1_LST3|00:02:54\n\
+             LST|1_OWD2|4300001982956429|S_0|11220|K \n\
+             LST|1_OWD45|EF000019096A4026|S_0|11150\n\
+             1_EVT|0:02:55\n",
+        );
+        assert_eq!(bus.devices.len(), 46);
+        assert_eq!(bus.devices[2].name(), "K");
+        assert_eq!(bus.devices[45].name(), "OWD45");
+        assert_eq!(bus.index("OWD45_1"), Some(45));
+        let res = feed(&mut bus, &mut routes, "1_OWD_45|5\n");
+        assert_eq!(retained(&res, "ESERA/1/OWD45/status").unwrap(), "offline");
+        // out of range
+        feed(&mut bus, &mut routes, "1_OWD_99|5\n");
+        // OWD45 is not listed anymore
+        let res = feed(
+            &mut bus,
+            &mut routes,
+            "1_LST3|00:03:54\n\
+             LST|1_OWD2|4300001982956429|S_0|11220|K \n\
+             1_EVT|0:03:55\n",
+        );
+        assert_eq!(retained(&res, "ESERA/1/OWD45/status").unwrap(), "offline");
+        assert!(!bus.devices[45].configured());
+        assert_eq!(bus.index("OWD45_1"), None);
+    }
+
+    #[test]
+    fn publish_settings() {
+        let (mut bus, mut routes) = bus_with(Config::default(), "");
+        let res = feed(
+            &mut bus,
+            &mut routes,
+            "1_CSE|0:02:40\n1_KALSENDTIME|60\n1_KALALARM|0|2|1\n1_DIO|3\n",
+        );
+        let settings: serde_json::Value =
+            serde_json::from_str(&retained(&res, "ESERA/1/settings").unwrap()).unwrap();
+        assert_eq!(settings["kalsendtime"], 60);
+        assert_eq!(settings["kalalarm"], json!([0, 2, 1]));
+        assert_eq!(settings["dio"], 3);
+    }
+
+    #[test]
+    fn retract_stale_discovery() {
+        let mut conf = Config::default();
+        conf.discovery.purge = true;
+        let (mut bus, mut routes) = bus_with(
+            conf,
+            "1_LST3|00:02:54\n\
+             LST|1_OWD1|4300001982956429|S_0|11220|K \n\
+             1_EVT|0:02:55\n",
+        );
+        let old = "homeassistant/switch/1/4300001982956429_ch1/config";
+        assert!(bus.announced.contains(old));
+        assert_eq!(bus.purge(old, "{}"), None);
+        assert_eq!(
+            bus.purge("homeassistant/switch/1/0000_ch1/config", "{}"),
+            Some(MqttMsg::retract("homeassistant/switch/1/0000_ch1/config"))
+        );
+        assert_eq!(
+            bus.purge("homeassistant/switch/2/0000_ch1/config", "{}"),
+            None
+        );
+        let res = feed(
+            &mut bus,
+            &mut routes,
+            "1_LST3|00:03:54\n\
+             LST|1_OWD1|4300001982956430|S_0|11220|K \n\
+             1_EVT|0:03:55\n",
+        );
+        assert!(res.mqtt.contains(&MqttMsg::retract(old)));
+        assert!(res.mqtt.contains(&MqttMsg::Sub {
+            topic: "homeassistant/+/1/+/config".into()
+        }));
+        assert!(!bus.announced.contains(old));
+    }
+
+    #[test]
+    fn rescan_leaves_known_devices_alone() {
+        let (mut bus, mut routes) = bus_with(Config::default(), "");
+        let lst = "1_LST3|00:02:54\n\
+                   LST|1_OWD1|4300001982956429|S_0|11220|K \n\
+                   1_EVT|0:02:55\n";
+        let discovery = |res: &TwoWay| {
+            res.mqtt
+                .iter()
+                .filter(|m| {
+                    matches!(
+                        m,
+                        MqttMsg::Pub {
+                            kind: Kind::Discovery,
+                            ..
+                        }
+                    )
+                })
+                .count()
+        };
+        let res = feed(&mut bus, &mut routes, lst);
+        assert!(cmds(&res).contains(&"SET,SYS,OUTA,500".to_string()));
+        let initial = discovery(&res);
+        assert!(initial > 0);
+        let res = feed(&mut bus, &mut routes, lst);
+        assert_eq!(cmds(&res), Vec::<String>::new());
+        assert_eq!(discovery(&res), 0);
+        assert!(!res.mqtt.iter().any(|m| matches!(m, MqttMsg::Sub { .. })));
+        // a replaced device is announced on its own
+        let res = feed(
+            &mut bus,
+            &mut routes,
+            "1_LST3|00:03:54\n\
+             LST|1_OWD1|4300001982956430|S_0|11220|K \n\
+             1_EVT|0:03:55\n",
+        );
+        assert_eq!(cmds(&res), Vec::<String>::new());
+        assert!(discovery(&res) > 0);
+        assert!(res
+            .mqtt
+            .iter()
+            .filter(|m| matches!(
+                m,
+                MqttMsg::Pub {
+                    kind: Kind::Discovery,
+                    ..
+                }
+            ))
+            .all(|m| m.topic().contains("/43000019829564")));
+        // everything is set up again after a controller reset
+        feed(&mut bus, &mut routes, "1_RST|1\n");
+        let res = feed(&mut bus, &mut routes, lst);
+        assert!(cmds(&res).contains(&"SET,SYS,OUTA,500".to_string()));
+        assert!(discovery(&res) >= initial);
+    }
+
+    #[test]
+    fn ignore_invalid_slots() {
+        let (bus, _) = bus_with(
+            Config::default(),
+            "1_LST3|00:02:54\n\
+             LST|1_OWD1|4300001982956429|S_0|11220|K \n\
+             LST|1_OWD256|4300001982956431|S_0|11220|M \n\
+             LST|1_OWD99999999999|4300001982956432|S_0|11220|N \n\
+             1_EVT|0:02:55\n",
+        );
+        assert_eq!(bus.devices.len(), 2);
+    }
+
+    #[test]
+    fn republish_states() {
+        let (mut bus, mut routes) = bus_with(
+            Config::default(),
+            "1_LST3|00:02:54\n\
+             LST|1_OWD1|4300001982956429|S_0|11220|K \n\
+             1_EVT|0:02:55\n",
+        );
+        feed(&mut bus, &mut routes, "1_OWD1_3|5\n");
+        feed(&mut bus, &mut routes, "1_OWD1_3|4\n");
+        let res = bus.republish();
+        assert!(res.contains(&MqttMsg::retain("ESERA/1/K/status", "online")));
+        assert!(res.contains(&MqttMsg::new("ESERA/1/K/out/ch1", "0")));
+        assert!(res.iter().any(|m| matches!(
+            m,
+            MqttMsg::Pub {
+                kind: Kind::Discovery,
+                ..
+            }
+        )));
+    }
+
+    #[test]
+    fn restore_outputs_after_reset() {
+        let lst = "1_LST3|00:02:54\n\
+                   LST|1_OWD1|4300001982956429|S_0|11220|K \n\
+                   1_EVT|0:02:55\n";
+        let conf = Config {
+            restore: Restore::Last,
+            ..Config::default()
+        };
+        let (mut bus, mut routes) = bus_with(conf, lst);
+        feed(&mut bus, &mut routes, "1_OWD1_3|5\n");
+        feed(&mut bus, &mut routes, "1_RST|1\n");
+        feed(&mut bus, &mut routes, "1_OWD1_3|0\n");
+        let res = feed(&mut bus, &mut routes, lst);
+        let sent = cmds(&res);
+        for cmd in &[
+            "SET,OWD,OUT,1,0,1",
+            "SET,OWD,OUT,1,1,0",
+            "SET,OWD,OUT,1,2,1",
+        ] {
+            assert!(sent.contains(&cmd.to_string()), "{} not in {:?}", cmd, sent);
+        }
+        // only once
+        let res = feed(&mut bus, &mut routes, lst);
+        assert!(cmds(&res).iter().all(|c| !c.starts_with("SET,OWD,OUT")));
+    }
+
+    #[test]
+    fn restore_saved_outputs_on_startup() {
+        let dir = std::env::temp_dir().join(format!("esera-bus-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let conf = Config {
+            restore: Restore::Last,
+            state_dir: Some(dir.clone()),
+            ..Config::default()
+        };
+        let lst = "1_LST3|00:02:54\n\
+                   LST|1_OWD1|4300001982956429|S_0|11220|K \n\
+                   LST|1_OWD2|EF000019096A4026|S_0|11221|D \n\
+                   1_EVT|0:02:55\n";
+        let (mut bus, mut routes) = bus_with(conf.clone(), "");
+        let res = feed(&mut bus, &mut routes, lst);
+        assert!(cmds(&res).iter().all(|c| !c.starts_with("SET,OWD,OUT")));
+        bus.handle_mqtt(1, &MqttMsg::new("ESERA/1/K/set/ch2", "1"), 1)
+            .unwrap();
+        bus.handle_mqtt(2, &MqttMsg::new("ESERA/1/D/set/ch1", "20"), 1)
+            .unwrap();
+        // bridge restart
+        let (mut bus, mut routes) = bus_with(conf, "");
+        let res = feed(&mut bus, &mut routes, lst);
+        assert!(cmds(&res).contains(&"SET,OWD,OUT,1,0,0".to_string()));
+        assert!(cmds(&res).contains(&"SET,OWD,OUT,1,1,1".to_string()));
+        assert!(cmds(&res).contains(&"SET,OWD,DIM,2,1,20".to_string()));
+        // unknown dimmer channel is left alone
+        assert!(cmds(&res)
+            .iter()
+            .all(|c| !c.starts_with("SET,OWD,DIM,2,2,")));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn restore_policy_per_device() {
+        let mut conf = Config {
+            restore: Restore::Off,
+            ..Config::default()
+        };
+        conf.devices.insert(
+            "1/D".into(),
+            crate::config::DeviceConf {
+                restore: Some(Restore::Leave),
+                ..Default::default()
+            },
+        );
+        let lst = "1_LST3|00:02:54\n\
+                   LST|1_OWD1|4300001982956429|S_0|11220|K \n\
+                   LST|1_OWD2|EF000019096A4026|S_0|11221|D \n\
+                   1_EVT|0:02:55\n";
+        let (mut bus, mut routes) = bus_with(conf, lst);
+        feed(&mut bus, &mut routes, "1_OWD1_3|5\n");
+        feed(&mut bus, &mut routes, "1_OWD2_3|20\n");
+        feed(&mut bus, &mut routes, "1_RST|1\n");
+        let res = feed(&mut bus, &mut routes, lst);
+        assert!(cmds(&res).contains(&"SET,OWD,OUT,1,0,0".to_string()));
+        assert!(cmds(&res).contains(&"SET,OWD,OUT,1,2,0".to_string()));
+        assert!(cmds(&res).iter().all(|c| !c.starts_with("SET,OWD,DIM")));
+        // failures are reported by the device which issued the command
+        assert!(res
+            .ow
+            .iter()
+            .filter(|(_, c)| c.to_string().starts_with("SET,OWD,OUT"))
+            .all(|(dev, _)| *dev == 1));
+    }
+
+    #[test]
+    fn bitstring_subaddrs() {
+        let mut conf = Config::default();
+        conf.devices.insert(
+            "1/K".into(),
+            crate::config::DeviceConf {
+                bitstrings: true,
+                ..Default::default()
+            },
+        );
+        let (mut bus, mut routes) = bus_with(
+            conf,
+            "1_LST3|00:02:54\n\
+             LST|1_OWD1|4300001982956429|S_0|11220|K \n\
+             1_EVT|0:02:55\n",
+        );
+        assert_eq!(bus.index("OWD1_4"), Some(1));
+        assert_eq!(bus.index("OWD1_3"), None);
+        let res = feed(&mut bus, &mut routes, "1_OWD1_4|00000101\n");
+        let outputs: Vec<_> = res
+            .mqtt
+            .iter()
+            .filter_map(|m| match m {
+                MqttMsg::Pub { topic, payload, .. } if topic.starts_with("ESERA/1/K/out/") => {
+                    Some(payload.as_str())
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(outputs, ["1", "0", "1", "0", "0", "0", "0", "0"]);
+        // decimal form is ignored and does not trigger a rescan
+        bus.last_scan = None;
+        let res = feed(&mut bus, &mut routes, "1_OWD1_3|5\n");
+        assert!(res.mqtt.is_empty() && res.ow.is_empty());
+    }
+
+    #[test]
+    fn publish_error_counters() {
+        let (mut bus, mut routes) = bus_with(Config::default(), "");
+        let res = feed(
+            &mut bus,
+            &mut routes,
+            "1_LST3|00:02:54\n\
+             LST|1_OWD1|4300001982956429|S_0|11220|K \n\
+             LST|1_OWD2|FFFFFFFFFFFFFFFF|S_10|none\n\
+             1_EVT|0:02:55\n",
+        );
+        assert!(res
+            .mqtt
+            .iter()
+            .any(|m| matches!(m, MqttMsg::Pub { topic, .. }
+            if topic == "homeassistant/sensor/1/4300001982956429_errors/config")));
+        let res = feed(&mut bus, &mut routes, "1_ERRSUM|12\n");
+        assert_eq!(res.mqtt, [MqttMsg::new("ESERA/1/SYS/errors", "12")]);
+        let res = feed(
+            &mut bus,
+            &mut routes,
+            "1_ERR|0:03:02\n1_ERROWD1|3\n1_ERROWD2|0\n1_EVT|0:03:04\n",
+        );
+        // empty slots are not reported
+        assert_eq!(res.mqtt, [MqttMsg::new("ESERA/1/K/errors", "3")]);
+        assert_eq!(bus.errors[&1], 3);
+        // replacement starts from scratch
+        feed(
+            &mut bus,
+            &mut routes,
+            "1_LST3|00:03:54\n\
+             LST|1_OWD1|4300001982956430|S_0|11220|K \n\
+             1_EVT|0:03:55\n",
+        );
+        assert_eq!(bus.errors.get(&1), None);
+    }
+
+    #[test]
+    fn publish_inventory() {
+        let (mut bus, mut routes) = bus_with(Config::default(), "");
+        let res = feed(
+            &mut bus,
+            &mut routes,
+            "1_LST3|00:02:54\n\
+             LST|1_OWD1|4300001982956429|S_0|11220|K \n\
+             LST|1_OWD2|FFFFFFFFFFFFFFFF|S_10|none\n\
+             1_EVT|0:02:55\n",
+        );
+        let inv: serde_json::Value =
+            serde_json::from_str(&retained(&res, "ESERA/1/inventory").unwrap()).unwrap();
+        assert_eq!(inv.as_array().unwrap().len(), 2);
+        let k = &inv[1];
+        assert_eq!(k["busid"], "OWD1");
+        assert_eq!(k["model"], "Switch8");
+        assert_eq!(k["name"], "K");
+        assert_eq!(k["status"], "online");
+        let topics = k["topics"].as_array().unwrap();
+        assert!(topics.contains(&json!("ESERA/1/K/set/ch1")));
+        assert!(topics.contains(&json!("ESERA/1/K/name/set")));
+        // taken from discovery data, whether states have been seen or not
+        assert!(topics.contains(&json!("ESERA/1/K/in/ch1")));
+        assert!(topics.contains(&json!("ESERA/1/K/out/ch1")));
+        assert!(topics.contains(&json!("ESERA/1/K/errors")));
+        assert!(!topics.contains(&json!("ESERA/status")));
+        assert!(k["discovery"]
+            .as_array()
+            .unwrap()
+            .contains(&json!("sensor/1/4300001982956429_errors")));
+        feed(&mut bus, &mut routes, "1_OWD1_1|1\n");
+        assert_eq!(bus.inventory()[1].topics, *topics);
+        let res = feed(&mut bus, &mut routes, "1_OWD_1|5\n");
+        assert!(retained(&res, "ESERA/1/inventory")
+            .unwrap()
+            .contains(r#""status":"offline""#));
+    }
+
+    #[test]
+    fn rename_rejects_collisions() {
+        let (mut bus, _) = bus_with(
+            Config::default(),
+            "1_LST3|00:02:54\n\
+             LST|1_OWD1|4300001982956429|S_0|11220|K \n\
+             LST|1_OWD2|4300001982956430|S_0|11220\n\
+             1_EVT|0:02:55\n",
+        );
+        for name in &["OWD1", "SYS", "OWD7", "K"] {
+            let res = bus
+                .handle_mqtt(2, &MqttMsg::retain("ESERA/1/OWD2/name/set", name), RENAME)
+                .unwrap();
+            assert!(res.ow.is_empty(), "{} accepted", name);
+            // the request is cleared nevertheless
+            assert_eq!(retained(&res, "ESERA/1/OWD2/name/set").unwrap(), "");
+        }
+        let res = bus
+            .handle_mqtt(1, &MqttMsg::retain("ESERA/1/K/name/set", "OWD1"), RENAME)
+            .unwrap();
+        assert_eq!(cmds(&res), ["SET,OWD,NAME,1,OWD1", "GET,OWB,LISTALL1"]);
+    }
+
+    #[test]
+    fn rename_device() {
+        let (mut bus, mut routes) = bus_with(
+            Config::default(),
+            "1_LST3|00:02:54\n\
+             LST|1_OWD1|4300001982956429|S_0|11220|K \n\
+             1_EVT|0:02:55\n",
+        );
+        feed(&mut bus, &mut routes, "1_OWD1_3|5\n");
+        assert_eq!(routes.lookup("ESERA/1/K/name/set"), &[(1, RENAME)]);
+        let res = bus
+            .handle_mqtt(1, &MqttMsg::retain("ESERA/1/K/name/set", "K9"), RENAME)
+            .unwrap();
+        assert_eq!(cmds(&res), ["SET,OWD,NAME,1,K9", "GET,OWB,LISTALL1"]);
+        assert_eq!(res.ow[0].0, 1);
+        assert_eq!(retained(&res, "ESERA/1/K/name/set").unwrap(), "");
+        // retraction echoed by the broker
+        let res = bus
+            .handle_mqtt(1, &MqttMsg::retain("ESERA/1/K/name/set", ""), RENAME)
+            .unwrap();
+        assert!(res.mqtt.is_empty() && res.ow.is_empty());
+        let res = feed(
+            &mut bus,
+            &mut routes,
+            "1_LST3|00:03:54\n\
+             LST|1_OWD1|4300001982956429|S_0|11220|K9\n\
+             1_EVT|0:03:55\n",
+        );
+        assert_eq!(
+            events(&res),
+            vec![
+                json!({"event": "renamed", "busid": "OWD1", "serno": "4300001982956429",
+                        "artno": "11220", "name": "K9", "old_name": "K"})
+            ]
+        );
+        assert_eq!(retained(&res, "ESERA/1/K/status").unwrap(), "");
+        assert_eq!(retained(&res, "ESERA/1/K9/status").unwrap(), "online");
+        assert!(routes.lookup("ESERA/1/K/set/ch1").is_empty());
+        assert_eq!(routes.lookup("ESERA/1/K9/set/ch1"), &[(1, 0)]);
+        assert!(bus.states.keys().all(|t| !t.starts_with("ESERA/1/K/")));
+        // discovery entries are keyed by serial number and get updated in place
+        let topic = "homeassistant/switch/1/4300001982956429_ch1/config";
+        assert!(retained(&res, topic)
+            .unwrap()
+            .contains("ESERA/1/K9/set/ch1"));
+        assert!(res.mqtt.iter().all(
+            |m| !matches!(m, MqttMsg::Pub { payload, .. } if payload.is_empty()
+                && m.topic().starts_with("homeassistant/"))
+        ));
+    }
+}
@@ -0,0 +1,398 @@
+//! Recording and replay of controller sessions
+//!
+//! A capture contains the raw data exchanged with a controller, one chunk per line. Each line
+//! starts with the time since the start of the recording (seconds), followed by the direction
+//! (`<` received from the controller, `>` sent to the controller) and the data itself. Line breaks
+//! and other non-printable characters are escaped:
+//!
+//! ```text
+//! # 10.2.3.4:5000 2020-11-07 21:02:40
+//! 0.000 > SET,SYS,DATAPRINT,1\r\n
+//! 0.041 < 1_DATAPRINT|1\r\n
+//! 3.507 < 1_OWD1_1|2184\r\n1_OWD1_3|5120\r\n
+//! ```
+use crossbeam::channel::{self, Sender};
+use std::fmt::{self, Write as _};
+use std::fs::{self, File};
+use std::io::{self, LineWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf};
+use tokio::time::{sleep_until, Instant};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Capture line {0}: {1}")]
+    Parse(usize, String),
+    #[error(transparent)]
+    IO(#[from] io::Error),
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Transfer direction, as seen from the bridge
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dir {
+    /// Received from the controller
+    Rx,
+    /// Sent to the controller
+    Tx,
+}
+
+impl Dir {
+    fn symbol(self) -> char {
+        match self {
+            Dir::Rx => '<',
+            Dir::Tx => '>',
+        }
+    }
+}
+
+/// Data chunk with its time of transfer relative to the start of the recording
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub time: Duration,
+    pub dir: Dir,
+    pub data: Vec<u8>,
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:.3} {} {}",
+            self.time.as_secs_f64(),
+            self.dir.symbol(),
+            escape(&self.data)
+        )
+    }
+}
+
+fn escape(data: &[u8]) -> String {
+    let mut s = String::with_capacity(data.len());
+    for &b in data {
+        match b {
+            b'\\' => s.push_str("\\\\"),
+            b'\r' => s.push_str("\\r"),
+            b'\n' => s.push_str("\\n"),
+            b' '..=b'~' => s.push(b as char),
+            _ => write!(s, "\\x{:02x}", b).expect("infallible"),
+        }
+    }
+    s
+}
+
+fn unescape(s: &str) -> Result<Vec<u8>, String> {
+    let mut data = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            data.push(b);
+            continue;
+        }
+        match bytes.next() {
+            Some(b'\\') => data.push(b'\\'),
+            Some(b'r') => data.push(b'\r'),
+            Some(b'n') => data.push(b'\n'),
+            Some(b'x') => {
+                let hex: Vec<u8> = bytes.by_ref().take(2).collect();
+                let hex = std::str::from_utf8(&hex).unwrap_or_default();
+                data.push(
+                    u8::from_str_radix(hex, 16)
+                        .map_err(|_| format!("invalid escape '\\x{}'", hex))?,
+                );
+            }
+            other => {
+                return Err(format!(
+                    "invalid escape '\\{}'",
+                    other.map(char::from).unwrap_or(' ')
+                ))
+            }
+        }
+    }
+    Ok(data)
+}
+
+/// Writes a capture while a session is going on. Entries are passed to a background thread so
+/// that file I/O does not block the async runtime.
+pub struct Recorder {
+    tx: Option<Sender<String>>,
+    writer: Option<thread::JoinHandle<io::Result<()>>>,
+    start: Instant,
+}
+
+impl Recorder {
+    /// Starts a new recording. `title` goes into the file's header comment.
+    pub fn new<W: Write + Send + 'static>(mut out: W, title: &str) -> io::Result<Self> {
+        writeln!(out, "# {}", title)?;
+        let (tx, rx) = channel::unbounded::<String>();
+        let writer = thread::Builder::new()
+            .name("capture writer".into())
+            .spawn(move || {
+                for line in rx {
+                    writeln!(out, "{}", line)?;
+                }
+                out.flush()
+            })?;
+        Ok(Self {
+            tx: Some(tx),
+            writer: Some(writer),
+            start: Instant::now(),
+        })
+    }
+
+    /// Records into a file. The file is flushed after each entry so that nothing gets lost if the
+    /// bridge crashes.
+    pub fn create<P: AsRef<Path>>(path: P, title: &str) -> io::Result<Self> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        Self::new(LineWriter::new(File::create(path)?), title)
+    }
+
+    /// Queues an entry for writing. Fails with the writer's error if a previous entry could not
+    /// be written.
+    pub fn write(&mut self, dir: Dir, data: &[u8]) -> io::Result<()> {
+        let entry = Entry {
+            time: self.start.elapsed(),
+            dir,
+            data: data.to_vec(),
+        };
+        match &self.tx {
+            Some(tx) if tx.send(entry.to_string()).is_ok() => Ok(()),
+            _ => self.close(),
+        }
+    }
+
+    /// Waits until all entries have been written.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.close()
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        // disconnecting the channel stops the writer
+        self.tx = None;
+        match self.writer.take() {
+            Some(w) => w
+                .join()
+                .unwrap_or_else(|_| Err(io::Error::other("writer panicked"))),
+            None => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "recording has been stopped",
+            )),
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if self.writer.is_some() {
+            self.close().ok();
+        }
+    }
+}
+
+impl fmt::Debug for Recorder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Recorder")
+            .field("start", &self.start)
+            .finish()
+    }
+}
+
+/// Recorded session
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Capture {
+    pub entries: Vec<Entry>,
+}
+
+impl Capture {
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
+        fs::read_to_string(path)?.parse()
+    }
+
+    /// Data sent to the controller, split into lines
+    pub fn sent(&self) -> Vec<String> {
+        let data: Vec<u8> = self
+            .entries
+            .iter()
+            .filter(|e| e.dir == Dir::Tx)
+            .flat_map(|e| e.data.iter().copied())
+            .collect();
+        String::from_utf8_lossy(&data)
+            .lines()
+            .map(|l| l.trim().to_owned())
+            .filter(|l| !l.is_empty())
+            .collect()
+    }
+}
+
+impl FromStr for Capture {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut entries = Vec::new();
+        for (n, line) in s.lines().enumerate() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let err = |e: String| Error::Parse(n + 1, e);
+            let mut fields = line.splitn(3, ' ');
+            // rejects negative, infinite and NaN values which `Duration` cannot represent
+            let time = fields
+                .next()
+                .unwrap_or_default()
+                .parse()
+                .ok()
+                .and_then(|t| Duration::try_from_secs_f64(t).ok())
+                .ok_or_else(|| err("invalid time".into()))?;
+            let dir = match fields.next() {
+                Some("<") => Dir::Rx,
+                Some(">") => Dir::Tx,
+                _ => return Err(err("direction must be either '<' or '>'".into())),
+            };
+            entries.push(Entry {
+                time,
+                dir,
+                data: unescape(fields.next().unwrap_or_default()).map_err(err)?,
+            });
+        }
+        Ok(Self { entries })
+    }
+}
+
+/// Plays back the controller's part of a capture. The returned streams can be passed to
+/// [`ControllerConnection::from_streams`](crate::ControllerConnection::from_streams). Received
+/// data is fed in with the original timing divided by `speed`, so 1.0 reproduces the session in
+/// real time and `f64::INFINITY` replays it without delays. Anything written to the streams is
+/// discarded. The stream is closed after the last entry.
+///
+/// Must be called from within a Tokio runtime. Panics unless `speed` is positive.
+pub fn replay(capture: Capture, speed: f64) -> (ReadHalf<DuplexStream>, WriteHalf<DuplexStream>) {
+    assert!(speed > 0.0, "Replay speed must be positive (got {})", speed);
+    let (local, remote) = tokio::io::duplex(1 << 16);
+    tokio::spawn(async move {
+        if let Err(e) = play(capture, speed, remote).await {
+            debug!("Replay aborted: {}", e);
+        }
+    });
+    tokio::io::split(local)
+}
+
+async fn play(capture: Capture, speed: f64, remote: DuplexStream) -> io::Result<()> {
+    let (mut rx, mut tx) = tokio::io::split(remote);
+    let start = Instant::now();
+    let mut buf = [0; 1 << 10];
+    for entry in capture.entries.into_iter().filter(|e| e.dir == Dir::Rx) {
+        let delay = Duration::try_from_secs_f64(entry.time.as_secs_f64() / speed)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let due = start + delay;
+        loop {
+            tokio::select! {
+                _ = sleep_until(due) => break,
+                res = rx.read(&mut buf) => match res? {
+                    0 => return Ok(()),
+                    len => debug!(
+                        "Replay ignores {}",
+                        String::from_utf8_lossy(&buf[..len]).trim()
+                    ),
+                }
+            }
+        }
+        tx.write_all(&entry.data).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use parking_lot::Mutex;
+    use std::sync::Arc;
+
+    /// Shared buffer to look at what has been recorded
+    #[derive(Clone, Default)]
+    struct Buf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buf {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.0.lock().extend_from_slice(data);
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn record_and_parse() {
+        let buf = Buf::default();
+        let mut rec = Recorder::new(buf.clone(), "test").unwrap();
+        rec.write(Dir::Tx, b"GET,SYS,INFO\r\n").unwrap();
+        tokio::time::advance(Duration::from_millis(1500)).await;
+        rec.write(Dir::Rx, b"1_EVT|0:02:55\r\n1_\\\xff").unwrap();
+        rec.finish().unwrap();
+        let text = String::from_utf8(buf.0.lock().clone()).unwrap();
+        assert_eq!(
+            text,
+            "# test\n\
+             0.000 > GET,SYS,INFO\\r\\n\n\
+             1.500 < 1_EVT|0:02:55\\r\\n1_\\\\\\xff\n"
+        );
+        let cap: Capture = text.parse().unwrap();
+        assert_eq!(cap.entries.len(), 2);
+        assert_eq!(cap.entries[1].time, Duration::from_millis(1500));
+        assert_eq!(cap.entries[1].data, b"1_EVT|0:02:55\r\n1_\\\xff");
+        assert_eq!(cap.sent(), vec!["GET,SYS,INFO"]);
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(matches!(
+            "0.1 > ok\nx < 1_EVT".parse::<Capture>(),
+            Err(Error::Parse(2, _))
+        ));
+        assert!(matches!(
+            "0.1 ? 1".parse::<Capture>(),
+            Err(Error::Parse(1, _))
+        ));
+        assert!(matches!(
+            "0.1 < \\q".parse::<Capture>(),
+            Err(Error::Parse(1, _))
+        ));
+        for time in &["-1", "NaN", "inf"] {
+            assert!(matches!(
+                format!("{} < x", time).parse::<Capture>(),
+                Err(Error::Parse(1, _))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "Replay speed must be positive")]
+    async fn replay_rejects_zero_speed() {
+        replay(Capture::default(), 0.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn replay_timing() {
+        let cap: Capture = "0.5 < 1_KAL|1\\r\\n\n\
+                            0.7 > SET,SYS,KAL,1\\r\\n\n\
+                            10.0 < 1_EVT|0:02:55\\r\\n\n"
+            .parse()
+            .unwrap();
+        let start = Instant::now();
+        let (mut rx, mut tx) = replay(cap, 2.0);
+        tx.write_all(b"GET,SYS,INFO\r\n").await.unwrap();
+        let mut data = String::new();
+        rx.read_to_string(&mut data).await.unwrap();
+        assert_eq!(data, "1_KAL|1\r\n1_EVT|0:02:55\r\n");
+        assert_eq!(start.elapsed(), Duration::from_secs(5));
+    }
+}
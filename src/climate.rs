@@ -1,7 +1,7 @@
-///! HVAC climate controller
+//! HVAC climate controller
 use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
-use slog::{debug, error, info, o, Logger};
+use slog::{debug, info, o, Logger};
 use strum_macros::EnumString;
 use strum_macros::IntoStaticStr;
 use thiserror::Error;
@@ -158,7 +158,7 @@ impl Climate {
 
     pub fn announce(&self) -> MqttMsg {
         debug!(self.log, "Announcing");
-        MqttMsg::retain(
+        MqttMsg::discovery(
             self.t("config"),
             serde_json::to_string(&self.discovery()).unwrap(),
         )
@@ -197,7 +197,7 @@ impl Climate {
         if let Some(aux_cmnd) = &self.conf.aux_cmnd {
             if self.aux_on != on {
                 info!(self.log, "Setting auxiliary heating to {}", on);
-                return vec![MqttMsg::new(aux_cmnd.to_string(), bool2str(on))];
+                return vec![MqttMsg::command(aux_cmnd.to_string(), bool2str(on))];
             }
         }
         Vec::new()
@@ -272,7 +272,7 @@ impl Climate {
         if self.mode == Mode::Off {
             if self.heating_on {
                 info!(self.log, "Turning heating off ({} disabled)", self.name);
-                res.push(MqttMsg::new(&self.conf.heat_cmnd, bool2str(false)));
+                res.push(MqttMsg::command(&self.conf.heat_cmnd, bool2str(false)));
             }
             res.extend(self.set_aux(false));
             return res;
@@ -290,7 +290,7 @@ impl Climate {
                     self.log,
                     "Turning heating on ({}={:.2} °C)", self.name, self.temp_cur
                 );
-                res.push(MqttMsg::new(&self.conf.heat_cmnd, bool2str(true)));
+                res.push(MqttMsg::command(&self.conf.heat_cmnd, bool2str(true)));
                 // Use auxiliary heating to bridge larger temperature gaps
                 if self.temp_cur < self.temp_set - AUX_HEAT_TRIGGER {
                     res.extend(self.set_aux(true));
@@ -301,7 +301,7 @@ impl Climate {
                     self.log,
                     "Turning heating off ({}={:.2} °C)", self.name, self.temp_cur
                 );
-                res.push(MqttMsg::new(&self.conf.heat_cmnd, bool2str(false)));
+                res.push(MqttMsg::command(&self.conf.heat_cmnd, bool2str(false)));
             }
             _ => (),
         }
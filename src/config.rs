@@ -0,0 +1,250 @@
+//! Configuration file for esera-bridge
+//!
+//! Example:
+//!
+//! ```toml
+//! controllers = ["10.2.3.4", "10.2.3.5:5000"]
+//!
+//! [mqtt]
+//! host = "mqtt.example.com"
+//! cred = "user:password"
+//! prefix = "site/house1/esera"
+//! qos = { command = 1 }
+//! tls = { ca = "/etc/ssl/mqtt-ca.pem" }
+//!
+//! [discovery]
+//! enabled = true
+//!
+//! [timing]
+//! reconnect = 5
+//!
+//! [devices."1/R1"]
+//! close_time = 45.0
+//! open_time = 52.5
+//! ```
+use crate::state::Restore;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Cannot read config file {0}: {1}")]
+    Read(String, #[source] std::io::Error),
+    #[error("Cannot parse config file {0}: {1}")]
+    Parse(String, #[source] toml::de::Error),
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Controller addresses (HOST|IP[:PORT])
+    pub controllers: Vec<String>,
+    /// Controller port number if not given in the address
+    pub default_port: Option<u16>,
+    pub mqtt: Mqtt,
+    pub discovery: Discovery,
+    pub timing: Timing,
+    /// What to do with outputs after a controller reset (off/last/leave)
+    pub restore: Restore,
+    /// Directory to keep output states across restarts
+    pub state_dir: Option<PathBuf>,
+    /// Directory to record controller sessions to (see [`crate::capture`])
+    pub record_dir: Option<PathBuf>,
+    /// Per-device overrides. Keys are of the form "<CONTNO>/<NAME>" where NAME is either the device
+    /// name as configured in the controller or the bus id (e.g., "OWD5").
+    pub devices: HashMap<String, DeviceConf>,
+}
+
+impl Config {
+    pub fn read<P: AsRef<Path>>(file: P) -> Result<Self> {
+        let file = file.as_ref();
+        let name = file.display().to_string();
+        let content = fs::read(file).map_err(|e| Error::Read(name.clone(), e))?;
+        toml::from_slice(&content).map_err(|e| Error::Parse(name, e))
+    }
+
+    /// Topic prefixes for devices and discovery.
+    pub fn prefix(&self) -> crate::Prefix {
+        crate::Prefix::new(&self.mqtt.prefix, &self.discovery.prefix)
+    }
+
+    /// Looks up device-specific settings.
+    pub fn device(&self, contno: u8, name: &str) -> Option<&DeviceConf> {
+        self.devices.get(&format!("{}/{}", contno, name))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Mqtt {
+    /// MQTT broker address
+    pub host: String,
+    /// MQTT broker port
+    pub port: u16,
+    /// MQTT credentials (username:password)
+    pub cred: String,
+    /// Base of all topics published by the bridge
+    pub prefix: String,
+    /// Client identifier. Defaults to "esera_mqtt.<PID>".
+    pub client_id: Option<String>,
+    /// Keepalive interval (seconds)
+    pub keepalive: u64,
+    pub qos: Qos,
+    /// Connect via TLS if present
+    pub tls: Option<Tls>,
+}
+
+impl Default for Mqtt {
+    fn default() -> Self {
+        Self {
+            host: "localhost".into(),
+            port: 1883,
+            cred: String::new(),
+            prefix: "ESERA".into(),
+            client_id: None,
+            keepalive: 60,
+            qos: Qos::default(),
+            tls: None,
+        }
+    }
+}
+
+/// MQTT quality of service levels (0, 1 or 2) per message kind
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Qos {
+    /// Device states and other informational messages
+    pub state: u8,
+    /// Command messages and subscriptions
+    pub command: u8,
+    /// Home Assistant discovery announcements
+    pub discovery: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Tls {
+    /// CA certificate(s) used to verify the broker (PEM)
+    pub ca: PathBuf,
+    /// Client certificate (PEM)
+    pub client_cert: Option<PathBuf>,
+    /// Client private key (PEM, either PKCS#1 RSA or PKCS#8)
+    pub client_key: Option<PathBuf>,
+    /// ALPN protocols to negotiate
+    #[serde(default)]
+    pub alpn: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Discovery {
+    /// Publish Home Assistant discovery announcements
+    pub enabled: bool,
+    /// Home Assistant discovery prefix
+    pub prefix: String,
+    /// Remove retained announcements of unknown devices from the broker
+    pub purge: bool,
+}
+
+impl Default for Discovery {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            prefix: "homeassistant".into(),
+            purge: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timing {
+    /// Delay before reconnecting to a lost controller (seconds)
+    pub reconnect: u64,
+    /// Interval of controller keepalive messages (seconds)
+    pub kalsendtime: u8,
+    /// Reconnect after this many keepalive messages have been missed
+    pub keepalive_misses: u32,
+    /// Controller expects keepalive messages from the bridge at this interval (seconds). 0
+    /// disables.
+    pub kalrectime: u8,
+    /// Interval of periodic device status reports (seconds)
+    pub datatime: u8,
+    /// Interval of periodic bus rescans (seconds). 0 disables.
+    pub rescan: u64,
+    /// Interval of 1-Wire error counter polls (seconds). 0 disables.
+    pub errors: u64,
+}
+
+impl Default for Timing {
+    fn default() -> Self {
+        Self {
+            reconnect: 5,
+            kalsendtime: 120,
+            keepalive_misses: 3,
+            kalrectime: 120,
+            datatime: 30,
+            rescan: 600,
+            errors: 300,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeviceConf {
+    /// Shutter: time needed to close completely (seconds)
+    pub close_time: Option<f32>,
+    /// Shutter: time needed to open completely (seconds)
+    pub open_time: Option<f32>,
+    /// Overrides the global restore policy
+    pub restore: Option<Restore>,
+    /// Digital I/O: subscribe to bitstring sub-addresses (e.g. OWD2_2) instead of decimal ones
+    pub bitstrings: bool,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_example() {
+        let conf: Config = toml::from_str(
+            r#"
+controllers = ["10.2.3.4", "10.2.3.5:5000"]
+
+[mqtt]
+host = "mqtt.example.com"
+qos = { command = 1 }
+
+[timing]
+reconnect = 10
+
+[devices."1/R1"]
+close_time = 45.0
+"#,
+        )
+        .unwrap();
+        assert_eq!(conf.controllers, vec!["10.2.3.4", "10.2.3.5:5000"]);
+        assert_eq!(conf.mqtt.host, "mqtt.example.com");
+        assert_eq!(conf.mqtt.port, 1883);
+        assert_eq!(conf.mqtt.qos.command, 1);
+        assert_eq!(conf.mqtt.qos.state, 0);
+        assert!(conf.discovery.enabled);
+        assert_eq!(conf.prefix(), crate::Prefix::default());
+        assert_eq!(conf.timing.reconnect, 10);
+        assert_eq!(conf.timing.datatime, 30);
+        assert_eq!(conf.device(1, "R1").unwrap().close_time, Some(45.0));
+        assert_eq!(conf.device(2, "R1"), None);
+    }
+
+    #[test]
+    fn reject_unknown_keys() {
+        assert!(toml::from_str::<Config>("[mqtt]\nhots = \"localhost\"\n").is_err());
+    }
+}
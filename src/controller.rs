@@ -1,17 +1,19 @@
-use crate::parser::{self, Msg, MsgKind, OW};
+use crate::capture::{Dir, Recorder};
+use crate::config::Timing;
+use crate::parser::{self, Command, Msg, MsgKind, Section, OW};
 
 use chrono::Local;
-use crossbeam::atomic::AtomicCell;
-use crossbeam::channel::{Receiver, Sender};
-use parking_lot::Mutex;
+use crossbeam::channel::Sender;
 use std::collections::VecDeque;
 use std::fmt;
-use std::io::prelude::*;
-use std::net::TcpStream;
-use std::net::ToSocketAddrs;
-use std::thread;
 use std::time::Duration;
 use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::oneshot;
+use tokio::time::{interval_at, sleep, sleep_until, timeout, Instant};
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -23,230 +25,543 @@ pub enum Error {
     Disconnected,
     #[error("Controller communication protocol error ({0})")]
     Controller(u16),
+    #[error("No keepalive from controller ({0} missed)")]
+    Keepalive(u32),
+    #[error("No response from controller to {0}")]
+    Timeout(Command),
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
+/// Give up if the controller does not send anything for this long.
+const READ_TIMEOUT: Duration = Duration::from_secs(300);
+/// The controller chokes on commands sent in quick succession.
+const SEND_DELAY: Duration = Duration::from_millis(50);
+/// Tolerated delay of controller keepalive messages.
+const KEEPALIVE_GRACE: Duration = Duration::from_secs(5);
+/// Commands without a response are considered successful if no error has been reported within
+/// this time. Commands with a response fail if it does not arrive in time.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Outcome of a controller command: the controller's response if the command has one.
+pub type Reply = oneshot::Receiver<Result<Option<OW>>>;
+
+/// Command to be sent to the controller. The outcome is reported back to the associated [`Reply`]
+/// handle (if any).
 #[derive(Debug)]
-pub struct ControllerConnection<S>
-where
-    S: Read + Write + fmt::Debug,
-{
-    pub queue: Mutex<VecDeque<Result<OW>>>,
-    pub contno: u8,
-    partial: Mutex<String>,
-    reader: Mutex<S>,
-    writer: Mutex<S>,
+pub struct Request {
+    pub cmd: Command,
+    reply: Option<oneshot::Sender<Result<Option<OW>>>>,
 }
 
-impl ControllerConnection<TcpStream> {
-    pub fn new<A: ToSocketAddrs + fmt::Debug>(addr: A) -> Result<Self> {
-        info!("Connecting to 1-Wire controller at {:?}", addr);
-        let conn = TcpStream::connect(&addr)?;
-        conn.set_nodelay(false)?;
-        conn.set_read_timeout(Some(Duration::new(300, 0)))?;
-        let reader = conn.try_clone().unwrap();
-        let c = Self::from_streams(reader, conn);
-        c.setup()?;
-        Ok(c)
+impl Request {
+    pub fn new(cmd: Command) -> (Self, Reply) {
+        let (tx, rx) = oneshot::channel();
+        (
+            Self {
+                cmd,
+                reply: Some(tx),
+            },
+            rx,
+        )
     }
+}
 
-    fn setup(&self) -> Result<()> {
-        self.send_line("SET,SYS,DATAPRINT,1".to_owned())?;
-        self.pick(MsgKind::Dataprint)?;
-        let now = Local::now();
-        self.send_line(format!("SET,SYS,DATE,{}", now.format("%d.%m.%y")))?;
-        self.pick(MsgKind::Date)?;
-        self.send_line(format!("SET,SYS,TIME,{}", now.format("%H:%M:%S")))?;
-        self.pick(MsgKind::Time)?;
-        self.send_line("SET,SYS,KALSENDTIME,120")?;
-        self.pick(MsgKind::Kalsendtime)?;
-        self.send_line("SET,SYS,DATATIME,30")?;
-        self.pick(MsgKind::Datatime)?;
-        self.send_line("SET,SYS,SAVE")?;
-        self.pick(MsgKind::Save)?;
-        Ok(())
+/// Fire-and-forget command
+impl From<Command> for Request {
+    fn from(cmd: Command) -> Self {
+        Self { cmd, reply: None }
     }
 }
 
-/// Moves raw data out of `partial` as far as the parser allows.
-fn munch(partial: &mut String) -> Option<Result<OW>> {
-    let res = parser::parse(partial).map(|(rem, resp)| (rem.len(), resp));
-    match res {
-        Ok((rem, resp)) => {
-            partial.replace_range(0..(partial.len() - rem), "");
-            Some(Ok(resp))
+/// Message kind the controller answers with, for commands which have a response.
+fn response_kind(cmd: &Command) -> Option<MsgKind> {
+    match cmd.key().1 {
+        "INFO" => Some(MsgKind::CSI),
+        "LISTALL1" => Some(MsgKind::List3),
+        "SETTING" => Some(MsgKind::Settings),
+        "ERRSUM" => Some(MsgKind::ErrSum),
+        "ERROWD" => Some(MsgKind::ErrOwd),
+        "ERRLISTALL1" => Some(MsgKind::ErrList),
+        "NAME" => Some(MsgKind::OWDName),
+        "DATAPRINT" => Some(MsgKind::Dataprint),
+        "DATE" => Some(MsgKind::Date),
+        "TIME" => Some(MsgKind::Time),
+        "KAL" => Some(MsgKind::Keepalive),
+        "KALSENDTIME" => Some(MsgKind::Kalsendtime),
+        "KALREC" => Some(MsgKind::Kalrec),
+        "KALRECTIME" => Some(MsgKind::Kalrectime),
+        "DATATIME" => Some(MsgKind::Datatime),
+        "SAVE" => Some(MsgKind::Save),
+        _ => None,
+    }
+}
+
+/// Command which has been sent, but whose outcome is not known yet. The controller processes
+/// commands in order and does not tag its responses, so errors are attributed to the oldest
+/// pending command.
+#[derive(Debug)]
+struct Pending {
+    cmd: Command,
+    expect: Option<MsgKind>,
+    deadline: Instant,
+    reply: Option<oneshot::Sender<Result<Option<OW>>>>,
+}
+
+impl Pending {
+    fn resolve(self, res: Result<Option<OW>>) {
+        if let Some(reply) = self.reply {
+            // receiver may have lost interest
+            reply.send(res).ok();
         }
-        Err(nom::Err::Incomplete(_)) => None, // try again later
-        Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
-            // delete one line
-            let err = nom::error::convert_error(partial.as_ref(), e);
-            partial.replace_range(0..(partial.find('\n').map(|p| p + 1).unwrap_or(1)), "");
-            Some(Err(Error::Parse(err)))
+    }
+}
+
+/// Splits a stream of controller output into parsed messages.
+#[derive(Debug, Default)]
+pub struct Codec {
+    partial: String,
+}
+
+impl Codec {
+    /// Appends raw data received from the controller.
+    pub fn extend(&mut self, data: &[u8]) {
+        self.partial.push_str(&String::from_utf8_lossy(data))
+    }
+
+    /// Moves raw data out of the buffer as far as the parser allows. Returns `None` if more data
+    /// is needed.
+    pub fn decode(&mut self) -> Option<Result<OW>> {
+        let partial = &mut self.partial;
+        let res = parser::parse(partial).map(|(rem, resp)| (rem.len(), resp));
+        match res {
+            Ok((rem, resp)) => {
+                partial.replace_range(0..(partial.len() - rem), "");
+                Some(Ok(resp))
+            }
+            Err(nom::Err::Incomplete(_)) => None, // try again later
+            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+                // delete one line
+                let err = nom::error::convert_error(partial.as_ref(), e);
+                partial.replace_range(0..(partial.find('\n').map(|p| p + 1).unwrap_or(1)), "");
+                Some(Err(Error::Parse(err)))
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ControllerConnection<R, W> {
+    pub queue: VecDeque<Result<OW>>,
+    pub contno: u8,
+    codec: Codec,
+    pending: VecDeque<Pending>,
+    /// Time of last data received
+    last_rx: Instant,
+    reader: R,
+    writer: W,
+    /// Raw I/O is written here if set
+    recorder: Option<Recorder>,
+}
+
+impl ControllerConnection<OwnedReadHalf, OwnedWriteHalf> {
+    pub async fn new<A: ToSocketAddrs + fmt::Debug>(addr: A, timing: &Timing) -> Result<Self> {
+        Self::connect(addr, timing, || None).await
+    }
+
+    /// Like [`new`](Self::new), but records the whole session (including the controller setup)
+    /// if `recorder` returns one. It is called only once the connection has been established.
+    pub async fn connect<A, F>(addr: A, timing: &Timing, recorder: F) -> Result<Self>
+    where
+        A: ToSocketAddrs + fmt::Debug,
+        F: FnOnce() -> Option<Recorder>,
+    {
+        let mut c = Self::open(addr, None).await?;
+        if let Some(rec) = recorder() {
+            c.record(rec);
         }
+        c.setup(timing).await?;
+        Ok(c)
+    }
+
+    /// Connects without changing the controller's configuration.
+    pub async fn open<A: ToSocketAddrs + fmt::Debug>(
+        addr: A,
+        recorder: Option<Recorder>,
+    ) -> Result<Self> {
+        info!("Connecting to 1-Wire controller at {:?}", addr);
+        let conn = TcpStream::connect(&addr).await?;
+        let (reader, writer) = conn.into_split();
+        let mut c = Self::from_streams(reader, writer);
+        c.recorder = recorder;
+        Ok(c)
     }
 }
 
-impl<S> ControllerConnection<S>
+impl<R, W> ControllerConnection<R, W>
 where
-    S: Read + Write + fmt::Debug,
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
 {
-    pub fn from_streams(reader: S, writer: S) -> Self {
+    pub fn from_streams(reader: R, writer: W) -> Self {
         Self {
-            queue: Mutex::new(VecDeque::default()),
+            queue: VecDeque::default(),
             contno: 0,
-            partial: Mutex::new(String::with_capacity(1 << 12)),
-            reader: Mutex::new(reader),
-            writer: Mutex::new(writer),
+            codec: Codec::default(),
+            pending: VecDeque::default(),
+            last_rx: Instant::now(),
+            reader,
+            writer,
+            recorder: None,
+        }
+    }
+
+    /// Writes all data exchanged with the controller from now on to `recorder`.
+    pub fn record(&mut self, recorder: Recorder) {
+        self.recorder = Some(recorder)
+    }
+
+    fn capture(&mut self, dir: Dir, data: &[u8]) {
+        if let Some(rec) = &mut self.recorder {
+            if let Err(e) = rec.write(dir, data) {
+                warn!(
+                    "[{}] Failed to record session, stopping: {}",
+                    self.contno, e
+                );
+                self.recorder = None;
+            }
+        }
+    }
+
+    async fn setup(&mut self, timing: &Timing) -> Result<()> {
+        use Section::SYS;
+        self.command(Command::set(SYS, "DATAPRINT", &[1])).await?;
+        let now = Local::now();
+        self.command(Command::set(SYS, "DATE", &[now.format("%d.%m.%y")]))
+            .await?;
+        self.command(Command::set(SYS, "TIME", &[now.format("%H:%M:%S")]))
+            .await?;
+        self.command(Command::set(SYS, "KALSENDTIME", &[timing.kalsendtime]))
+            .await?;
+        if timing.kalrectime > 0 {
+            self.command(Command::set(SYS, "KALRECTIME", &[timing.kalrectime]))
+                .await?;
+            self.command(Command::set(SYS, "KALREC", &[1])).await?;
+        } else {
+            self.command(Command::set(SYS, "KALREC", &[0])).await?;
+        }
+        self.command(Command::set(SYS, "DATATIME", &[timing.datatime]))
+            .await?;
+        self.command(Command::set::<u8>(SYS, "SAVE", &[])).await?;
+        Ok(())
+    }
+
+    /// Sends a command and waits for its outcome. Returns the controller's response if the
+    /// command has one. Other messages arriving in the meantime are queued.
+    pub async fn command(&mut self, cmd: Command) -> Result<Option<OW>> {
+        let (req, mut reply) = Request::new(cmd);
+        self.submit(req).await?;
+        let mut res = None;
+        self.process(|_| {
+            res = reply.try_recv().ok();
+            res.is_some()
+        })
+        .await?;
+        res.expect("resolved")
+    }
+
+    /// Sends a command without waiting for its outcome. The outcome is reported to the returned
+    /// [`Reply`] while further commands are processed, e.g. by [`settle`](Self::settle). Commands
+    /// are paced so that the controller keeps up.
+    pub async fn post(&mut self, cmd: Command) -> Result<Reply> {
+        let (req, reply) = Request::new(cmd);
+        self.submit(req).await?;
+        sleep(SEND_DELAY).await;
+        Ok(reply)
+    }
+
+    /// Waits until the outcome of all sent commands is known. Other messages arriving in the
+    /// meantime are queued.
+    pub async fn settle(&mut self) -> Result<()> {
+        self.process(|c| c.pending.is_empty()).await
+    }
+
+    /// Correlates incoming messages with pending commands until `done` returns true. Messages
+    /// which are not consumed by correlation are left in the queue.
+    async fn process<F: FnMut(&Self) -> bool>(&mut self, mut done: F) -> Result<()> {
+        let mut unrelated = VecDeque::new();
+        let res = loop {
+            while let Some(item) = self.queue.pop_front() {
+                if !self.correlate(&item) {
+                    unrelated.push_back(item);
+                }
+            }
+            self.expire();
+            if done(self) {
+                break Ok(());
+            }
+            let deadline = self.pending.front().map(|p| p.deadline);
+            tokio::select! {
+                res = self.receive() => match res {
+                    Ok(true) => (),
+                    Ok(false) => break Err(Error::Disconnected),
+                    Err(e) => break Err(e),
+                },
+                _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => (),
+            }
+        };
+        unrelated.append(&mut self.queue);
+        self.queue = unrelated;
+        res
+    }
+
+    /// Sends a command and registers it for response correlation.
+    async fn submit(&mut self, req: Request) -> Result<()> {
+        debug!("[{}] Submitting {:?}", self.contno, req.cmd);
+        self.send_line(req.cmd.to_string()).await?;
+        self.pending.push_back(Pending {
+            expect: response_kind(&req.cmd),
+            cmd: req.cmd,
+            deadline: Instant::now() + RESPONSE_TIMEOUT,
+            reply: req.reply,
+        });
+        Ok(())
+    }
+
+    /// Matches a controller message against pending commands. Returns true if the message has
+    /// been consumed.
+    fn correlate(&mut self, item: &Result<OW>) -> bool {
+        match item {
+            Ok(OW {
+                msg: Msg::Err(code),
+                ..
+            }) => match self.pending.pop_front() {
+                Some(p) => {
+                    p.resolve(Err(Error::Controller(*code)));
+                    true
+                }
+                None => false,
+            },
+            Ok(resp) => {
+                let kind = Some(MsgKind::from(&resp.msg));
+                if let Some(i) = self.pending.iter().position(|p| p.expect == kind) {
+                    // commands sent before have evidently been processed without error
+                    for p in self.pending.drain(..i) {
+                        p.resolve(Ok(None))
+                    }
+                    if let Some(p) = self.pending.pop_front() {
+                        p.resolve(Ok(Some(resp.clone())))
+                    }
+                }
+                false
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Resolves pending commands which did not receive an error in time. Commands which expect a
+    /// response fail if it has not arrived.
+    fn expire(&mut self) {
+        let now = Instant::now();
+        while self.pending.front().map(|p| p.deadline <= now) == Some(true) {
+            if let Some(p) = self.pending.pop_front() {
+                if p.expect.is_some() {
+                    debug!("[{}] No response to {}", self.contno, p.cmd);
+                    let err = Error::Timeout(p.cmd.clone());
+                    p.resolve(Err(err))
+                } else {
+                    p.resolve(Ok(None))
+                }
+            }
         }
     }
 
     /// Writes a single line to the underlaying stream. Newline will be appended.
-    pub fn send_line<L: Into<String>>(&self, line: L) -> Result<(), std::io::Error> {
+    pub async fn send_line<L: Into<String>>(&mut self, line: L) -> Result<(), std::io::Error> {
         let mut line = line.into();
         debug!("[{}] >>> {}", self.contno, line.trim());
         if !line.ends_with("\r\n") {
             line.push_str("\r\n");
         }
-        let mut w = self.writer.lock();
-        w.write_all(line.as_bytes())?;
-        w.flush()
+        self.capture(Dir::Tx, line.as_bytes());
+        self.writer.write_all(line.as_bytes()).await?;
+        self.writer.flush().await
     }
 
     /// Gets additional data from underlying stream and parses it as fas as possible.
-    /// Returns false if the underlying stream has been closed.
-    fn receive(&self) -> Result<bool> {
+    /// Returns false if the underlying stream has been closed. This method is cancel safe.
+    async fn receive(&mut self) -> Result<bool> {
         let mut buf = [0; 1 << 10];
-        let len = self.reader.lock().read(&mut buf)?;
+        let len = match timeout(READ_TIMEOUT, self.reader.read(&mut buf)).await {
+            Ok(res) => res?,
+            Err(_) => return Err(std::io::Error::from(std::io::ErrorKind::TimedOut).into()),
+        };
         if len == 0 {
             return Ok(false);
         }
-        let read = String::from_utf8_lossy(&buf[0..len]);
-        debug!("[{}] <<< {}", self.contno, read.trim());
-        let mut partial = self.partial.lock();
-        let mut queue = self.queue.lock();
-        partial.push_str(&read);
-        while let Some(resp) = munch(&mut partial) {
-            queue.push_back(resp);
+        self.last_rx = Instant::now();
+        self.capture(Dir::Rx, &buf[0..len]);
+        debug!(
+            "[{}] <<< {}",
+            self.contno,
+            String::from_utf8_lossy(&buf[0..len]).trim()
+        );
+        self.codec.extend(&buf[0..len]);
+        while let Some(resp) = self.codec.decode() {
+            self.queue.push_back(resp);
         }
         Ok(true)
     }
 
-    /// Returns top queue item or waits for new data if the queue is empty.
-    pub fn get(&self) -> Option<Result<OW>> {
-        while self.queue.lock().is_empty() {
-            thread::sleep(Duration::from_millis(10));
-            match self.receive() {
+    /// Returns top queue item or waits for new data if the queue is empty. Returns `None` if the
+    /// connection has been closed.
+    pub async fn get(&mut self) -> Option<Result<OW>> {
+        while self.queue.is_empty() {
+            match self.receive().await {
                 Ok(true) => (),
                 Ok(false) => return None,
                 Err(e) => return Some(Err(e)), // escalate transport errors quickly
             }
         }
-        self.queue.lock().pop_front()
+        self.queue.pop_front()
     }
 
-    pub fn csi(&mut self) -> Result<OW> {
-        self.send_line("GET,SYS,INFO")?;
-        let csi = self.pick(MsgKind::CSI)?;
+    pub async fn csi(&mut self) -> Result<OW> {
+        self.send_line(Command::get(Section::SYS, "INFO").to_string())
+            .await?;
+        let csi = self.pick(MsgKind::CSI).await?;
         self.contno = csi.contno;
         Ok(csi)
     }
 
-    pub fn list(&self) -> Result<OW> {
-        self.send_line("GET,OWB,LISTALL1")?;
-        self.pick(MsgKind::List3)
+    pub async fn list(&mut self) -> Result<OW> {
+        self.send_line(Command::get(Section::OWB, "LISTALL1").to_string())
+            .await?;
+        self.pick(MsgKind::List3).await
+    }
+
+    pub async fn settings(&mut self) -> Result<OW> {
+        self.send_line(Command::get(Section::SYS, "SETTING").to_string())
+            .await?;
+        self.pick(MsgKind::Settings).await
     }
 
     /// Pulls a message of the specified kind from the queue (out of order). Waits for more data
     /// until a message of the given kind is present.
-    pub fn pick(&self, kind: MsgKind) -> Result<OW> {
+    pub async fn pick(&mut self, kind: MsgKind) -> Result<OW> {
         loop {
-            {
-                let mut queue = self.queue.lock();
-                for (i, item) in queue.iter().enumerate() {
-                    if let Ok(resp) = item {
-                        if MsgKind::from(&resp.msg) == kind {
-                            return queue.remove(i).unwrap();
-                        }
-                        if let Msg::Err(e) = resp.msg {
-                            return Err(Error::Controller(e));
-                        }
+            for (i, item) in self.queue.iter().enumerate() {
+                if let Ok(resp) = item {
+                    if MsgKind::from(&resp.msg) == kind {
+                        return self.queue.remove(i).unwrap();
+                    }
+                    if let Msg::Err(e) = resp.msg {
+                        return Err(Error::Controller(e));
                     }
                 }
             }
             // item not already present in queue, wait for more data
-            thread::sleep(Duration::from_millis(10));
-            match self.receive() {
-                Ok(true) => (),
-                Ok(false) => return Err(Error::Disconnected),
-                Err(e) => return Err(e),
+            if !self.receive().await? {
+                return Err(Error::Disconnected);
             }
         }
     }
-}
 
-impl<S> ControllerConnection<S>
-where
-    S: Read + Write + fmt::Debug + Send,
-{
-    pub fn event_loop(&self, up: Receiver<String>, down: Sender<Result<OW>>) -> Result<()> {
-        let done = AtomicCell::new(false);
-        crossbeam::scope(|sc| {
-            let hdl = vec![
-                sc.builder()
-                    .name("reader".into())
-                    .spawn(|_| {
-                        while let Some(item) = self.get() {
-                            if down.send(item).is_err() {
-                                // channel closed
-                                done.store(true);
-                                return Ok(());
-                            }
-                            if done.load() {
-                                // other thread has exited
-                                return Ok(());
-                            }
-                        }
+    /// Passes controller events down to `down` and commands from `up` to the controller until
+    /// either the connection is lost or one of the channels is closed.
+    ///
+    /// The controller is expected to send something at least every `kalsendtime` seconds. Each
+    /// missed keepalive is reported as [`Error::Keepalive`]. The loop gives up after
+    /// `keepalive_misses`. If `kalrectime` is set, keepalive messages are sent to the controller
+    /// in turn.
+    pub async fn event_loop(
+        &mut self,
+        up: &mut UnboundedReceiver<Request>,
+        down: &Sender<Result<OW>>,
+        timing: &Timing,
+    ) -> Result<()> {
+        let kal = Duration::from_secs(timing.kalsendtime.into());
+        let mut missed = 0;
+        let period = Duration::from_secs(timing.kalrectime.max(2).into()) / 2;
+        let mut kalrec = interval_at(Instant::now() + period, period);
+        let period = Duration::from_secs(timing.rescan.max(1));
+        let mut rescan = interval_at(Instant::now() + period, period);
+        let period = Duration::from_secs(timing.errors.max(1));
+        let mut errors = interval_at(Instant::now() + period, period);
+        loop {
+            self.expire();
+            let mut reset = false;
+            while let Some(item) = self.queue.pop_front() {
+                if self.correlate(&item) {
+                    continue;
+                }
+                if let Ok(OW {
+                    msg: Msg::Rdy(_), ..
+                }) = item
+                {
+                    reset = true;
+                }
+                if down.send(item).is_err() {
+                    // channel closed
+                    return Ok(());
+                }
+            }
+            if reset {
+                // controller has rebooted and lost our settings
+                info!(
+                    "[{}] Controller ready after reset, reinitializing",
+                    self.contno
+                );
+                self.setup(timing).await?;
+                self.submit(Command::get(Section::SYS, "INFO").into())
+                    .await?;
+                self.submit(Command::get(Section::OWB, "LISTALL1").into())
+                    .await?;
+                self.submit(Command::get(Section::SYS, "SETTING").into())
+                    .await?;
+                continue;
+            }
+            let deadline = self.pending.front().map(|p| p.deadline);
+            let last_rx = self.last_rx;
+            tokio::select! {
+                res = self.receive() => {
+                    if !res? {
                         warn!("[{}] Controller connection unexpectely lost", self.contno);
-                        done.store(true);
-                        Err(Error::Disconnected)
-                    })
-                    .unwrap(),
-                sc.builder()
-                    .name("writer".into())
-                    .spawn(|_| {
-                        while let Ok(line) = up.recv() {
-                            if let Err(e) = self.send_line(line) {
-                                error!("[{}] Cannot send controller event: {}", self.contno, e);
-                                done.store(true);
-                                return Err(Error::Disconnected);
-                            }
-                            if done.load() {
-                                // other thread has exited
-                                return Ok(());
-                            }
-                            thread::sleep(Duration::from_millis(50));
-                        }
-                        done.store(true);
-                        // channel closed
-                        Ok(())
-                    })
-                    .unwrap(),
-            ];
-            hdl.into_iter().try_for_each(|h| h.join().unwrap())
-        })
-        .unwrap()
-    }
-}
-
-impl<S> Iterator for ControllerConnection<S>
-where
-    S: Read + Write + fmt::Debug,
-{
-    type Item = Result<OW>;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        self.get()
+                        return Err(Error::Disconnected);
+                    }
+                    missed = 0;
+                },
+                _ = sleep_until(last_rx + kal * (missed + 1) + KEEPALIVE_GRACE), if kal > Duration::ZERO => {
+                    missed += 1;
+                    warn!("[{}] Controller missed {} keepalive(s)", self.contno, missed);
+                    if missed >= timing.keepalive_misses {
+                        return Err(Error::Keepalive(missed));
+                    }
+                    if down.send(Err(Error::Keepalive(missed))).is_err() {
+                        return Ok(());
+                    }
+                },
+                _ = kalrec.tick(), if timing.kalrectime > 0 => {
+                    self.submit(Command::set(Section::SYS, "KAL", &[1]).into()).await?;
+                },
+                _ = rescan.tick(), if timing.rescan > 0 => {
+                    debug!("[{}] Periodic bus rescan", self.contno);
+                    self.submit(Command::get(Section::OWB, "LISTALL1").into()).await?;
+                },
+                _ = errors.tick(), if timing.errors > 0 => {
+                    self.submit(Command::get(Section::OWB, "ERRSUM").into()).await?;
+                    self.submit(Command::get(Section::OWB, "ERRLISTALL1").into()).await?;
+                },
+                req = up.recv() => match req {
+                    Some(req) => {
+                        self.submit(req).await?;
+                        sleep(SEND_DELAY).await;
+                    }
+                    None => return Ok(()),
+                },
+                _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => (),
+            }
+        }
     }
 }
 
@@ -257,15 +572,25 @@ mod test {
     use assert_matches::assert_matches;
     use bstr::B;
     use std::io::Cursor;
+    use tokio::io::{AsyncBufReadExt, DuplexStream, ReadHalf, WriteHalf};
 
-    #[test]
-    fn get_next_item() {
+    type TestConn = ControllerConnection<ReadHalf<DuplexStream>, WriteHalf<DuplexStream>>;
+
+    /// Connection whose controller side is played by the test via the returned stream
+    fn duplex_conn() -> (TestConn, DuplexStream) {
+        let (ctrl, remote) = tokio::io::duplex(1 << 10);
+        let (reader, writer) = tokio::io::split(ctrl);
+        (ControllerConnection::from_streams(reader, writer), remote)
+    }
+
+    #[tokio::test]
+    async fn get_next_item() {
         let mut c = ControllerConnection::from_streams(
             Cursor::new(B("1_EVT|21:02:43\n").to_vec()),
             Cursor::new(Vec::new()),
         );
         assert_matches!(
-            c.next(),
+            c.get().await,
             Some(Ok(OW {
                 msg: Msg::Evt(_),
                 ..
@@ -273,46 +598,64 @@ mod test {
         );
     }
 
-    #[test]
-    fn wait_on_closed_reader_should_fail() {
+    #[tokio::test]
+    async fn wait_on_closed_reader_should_fail() {
         let mut c = ControllerConnection::from_streams(
             Cursor::new(B("").to_vec()),
             Cursor::new(Vec::new()),
         );
-        assert_matches!(c.next(), None);
+        assert_matches!(c.get().await, None);
     }
 
-    #[test]
-    fn parse_garbage() {
+    #[tokio::test]
+    async fn parse_garbage() {
         let mut c = ControllerConnection::from_streams(
             Cursor::new(B("<BS>i������J���Ӈ��\n1_INF|21:28:53\n").to_vec()),
             Cursor::new(Vec::new()),
         );
-        assert_matches!(c.next(), Some(Err(Error::Parse(_))));
+        assert_matches!(c.get().await, Some(Err(Error::Parse(_))));
         assert_matches!(
-            c.next(),
+            c.get().await,
             Some(Ok(OW {
                 msg: Msg::Inf(_),
                 ..
             }))
         );
-        assert_matches!(c.next(), None);
+        assert_matches!(c.get().await, None);
+    }
+
+    #[tokio::test]
+    async fn record_session() {
+        let path = std::env::temp_dir().join(format!("esera-rec-{}.cap", std::process::id()));
+        let mut c = ControllerConnection::from_streams(
+            Cursor::new(B("1_EVT|21:02:43\n").to_vec()),
+            Cursor::new(Vec::new()),
+        );
+        c.record(Recorder::create(&path, "test").unwrap());
+        c.send_line("GET,SYS,INFO").await.unwrap();
+        assert_matches!(c.get().await, Some(Ok(_)));
+        c.recorder.take().unwrap().finish().unwrap();
+        let cap = crate::capture::Capture::read(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+        assert_eq!(cap.sent(), vec!["GET,SYS,INFO"]);
+        assert_eq!(cap.entries[1].dir, Dir::Rx);
+        assert_eq!(cap.entries[1].data, b"1_EVT|21:02:43\n");
     }
 
-    #[test]
-    fn pick_should_return_match() {
-        let c = ControllerConnection::from_streams(
+    #[tokio::test]
+    async fn pick_should_return_match() {
+        let mut c = ControllerConnection::from_streams(
             Cursor::new(B("1_DATE|20.09.20\n").to_vec()),
             Cursor::new(Vec::new()),
         );
-        let res = c.pick(MsgKind::Date).unwrap();
+        let res = c.pick(MsgKind::Date).await.unwrap();
         assert_eq!(res.msg, Msg::Date("20.09.20".into()));
-        assert!(c.queue.lock().is_empty());
+        assert!(c.queue.is_empty());
     }
 
-    #[test]
-    fn wait_should_cut_out_match() {
-        let c = ControllerConnection::from_streams(
+    #[tokio::test]
+    async fn wait_should_cut_out_match() {
+        let mut c = ControllerConnection::from_streams(
             Cursor::new(
                 B("1_KAL|1\n\
                1_DATAPRINT|1\n\
@@ -321,7 +664,7 @@ mod test {
             ),
             Cursor::new(Vec::new()),
         );
-        let res = c.pick(MsgKind::Dataprint).unwrap();
+        let res = c.pick(MsgKind::Dataprint).await.unwrap();
         assert_matches!(
             res,
             OW {
@@ -329,9 +672,232 @@ mod test {
                 ..
             }
         );
-        let mut q = c.queue.into_inner().into_iter().map(|r| r.unwrap());
+        let mut q = c.queue.into_iter().map(|r| r.unwrap());
         assert_eq!(q.next().unwrap().msg, Msg::Keepalive('1'));
         assert_eq!(q.next().unwrap().msg, Msg::Date("07.11.20".into()));
         assert_eq!(q.next(), None);
     }
+
+    #[tokio::test]
+    async fn event_loop_relays_both_directions() {
+        let (mut c, mut remote) = duplex_conn();
+        let (up_tx, mut up_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (down_tx, down_rx) = crossbeam::channel::unbounded();
+        let hdl =
+            tokio::spawn(
+                async move { c.event_loop(&mut up_rx, &down_tx, &Timing::default()).await },
+            );
+        up_tx
+            .send(Command::get(Section::SYS, "INFO").into())
+            .unwrap();
+        let mut buf = [0; 14];
+        remote.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"GET,SYS,INFO\r\n");
+        remote.write_all(b"1_KAL|1\n").await.unwrap();
+        assert_eq!(
+            tokio::task::spawn_blocking(move || down_rx.recv().unwrap())
+                .await
+                .unwrap()
+                .unwrap()
+                .msg,
+            Msg::Keepalive('1')
+        );
+        drop(remote);
+        assert_matches!(hdl.await.unwrap(), Err(Error::Disconnected));
+    }
+
+    #[tokio::test]
+    async fn command_errors_are_correlated() {
+        let (mut c, mut remote) = duplex_conn();
+        let (up_tx, mut up_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (down_tx, down_rx) = crossbeam::channel::unbounded();
+        tokio::spawn(async move { c.event_loop(&mut up_rx, &down_tx, &Timing::default()).await });
+        let (req, reply) = Request::new("SET,OWD,OUT,2,7,1".parse().unwrap());
+        up_tx.send(req).unwrap();
+        let (req, reply2) = Request::new("SET,SYS,DATE,07.11.20".parse().unwrap());
+        up_tx.send(req).unwrap();
+        let mut buf = [0; 42];
+        remote.read_exact(&mut buf).await.unwrap();
+        remote
+            .write_all(b"1_INF|0:02:46\n1_ERR|3\n1_DATE|07.11.20\n")
+            .await
+            .unwrap();
+        assert_matches!(reply.await.unwrap(), Err(Error::Controller(3)));
+        assert_matches!(
+            reply2.await.unwrap(),
+            Ok(Some(OW {
+                msg: Msg::Date(_),
+                ..
+            }))
+        );
+        // ERR has been consumed, INF and DATE are passed on
+        let rest: Vec<_> = tokio::task::spawn_blocking(move || {
+            down_rx.iter().take(2).map(|r| r.unwrap().msg).collect()
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            rest,
+            vec![Msg::Inf("0:02:46".into()), Msg::Date("07.11.20".into())]
+        );
+    }
+
+    #[tokio::test]
+    async fn command_without_response() {
+        let mut c = ControllerConnection::from_streams(
+            Cursor::new(B("1_KAL|1\n").to_vec()),
+            Cursor::new(Vec::new()),
+        );
+        // closed reader
+        assert_matches!(
+            c.command(Command::SysOut { ch: 1, on: true }).await,
+            Err(Error::Disconnected)
+        );
+        assert_eq!(c.queue.len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn command_timeout() {
+        let (mut c, _remote) = duplex_conn();
+        let start = Instant::now();
+        // no echo expected
+        assert_matches!(
+            c.command(Command::SysOut { ch: 1, on: true }).await,
+            Ok(None)
+        );
+        assert_eq!(start.elapsed(), RESPONSE_TIMEOUT);
+        assert_matches!(
+            c.command(Command::set(Section::SYS, "DATAPRINT", &[1]))
+                .await,
+            Err(Error::Timeout(_))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn settle_posted_commands() {
+        let (mut c, mut remote) = duplex_conn();
+        let start = Instant::now();
+        let mut first = c.post(Command::SysOut { ch: 1, on: true }).await.unwrap();
+        remote.write_all(b"1_ERR|3\n").await.unwrap();
+        let mut replies = Vec::new();
+        for ch in 2..=5 {
+            replies.push(c.post(Command::SysOut { ch, on: true }).await.unwrap());
+        }
+        c.settle().await.unwrap();
+        // error window is not awaited for each command
+        assert!(start.elapsed() < RESPONSE_TIMEOUT + Duration::from_secs(1));
+        assert_matches!(first.try_recv(), Ok(Err(Error::Controller(3))));
+        for mut r in replies {
+            assert_matches!(r.try_recv(), Ok(Ok(None)));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn watchdog_gives_up_after_missed_keepalives() {
+        let (mut c, _remote) = duplex_conn();
+        let (_up_tx, mut up_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (down_tx, down_rx) = crossbeam::channel::unbounded();
+        let timing = Timing {
+            kalsendtime: 10,
+            keepalive_misses: 2,
+            kalrectime: 0,
+            ..Timing::default()
+        };
+        let start = Instant::now();
+        assert_matches!(
+            c.event_loop(&mut up_rx, &down_tx, &timing).await,
+            Err(Error::Keepalive(2))
+        );
+        assert_eq!(start.elapsed(), Duration::from_secs(25));
+        assert_matches!(down_rx.try_recv(), Ok(Err(Error::Keepalive(1))));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn send_keepalive_to_controller() {
+        let (mut c, mut remote) = duplex_conn();
+        let (_up_tx, mut up_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (down_tx, _down_rx) = crossbeam::channel::unbounded();
+        let timing = Timing {
+            kalrectime: 60,
+            ..Timing::default()
+        };
+        tokio::spawn(async move { c.event_loop(&mut up_rx, &down_tx, &timing).await });
+        let start = Instant::now();
+        let mut buf = [0; 15];
+        remote.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"SET,SYS,KAL,1\r\n");
+        assert_eq!(start.elapsed(), Duration::from_secs(30));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn periodic_rescan() {
+        let (mut c, mut remote) = duplex_conn();
+        let (_up_tx, mut up_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (down_tx, _down_rx) = crossbeam::channel::unbounded();
+        let timing = Timing {
+            kalsendtime: 0,
+            kalrectime: 0,
+            rescan: 90,
+            ..Timing::default()
+        };
+        tokio::spawn(async move { c.event_loop(&mut up_rx, &down_tx, &timing).await });
+        let start = Instant::now();
+        let mut buf = [0; 18];
+        remote.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"GET,OWB,LISTALL1\r\n");
+        assert_eq!(start.elapsed(), Duration::from_secs(90));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn poll_error_counters() {
+        let (mut c, remote) = duplex_conn();
+        let (_up_tx, mut up_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (down_tx, _down_rx) = crossbeam::channel::unbounded();
+        let timing = Timing {
+            kalsendtime: 0,
+            kalrectime: 0,
+            rescan: 0,
+            errors: 60,
+            ..Timing::default()
+        };
+        tokio::spawn(async move { c.event_loop(&mut up_rx, &down_tx, &timing).await });
+        let start = Instant::now();
+        let mut lines = tokio::io::BufReader::new(remote).lines();
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "GET,OWB,ERRSUM");
+        assert_eq!(
+            lines.next_line().await.unwrap().unwrap(),
+            "GET,OWB,ERRLISTALL1"
+        );
+        assert_eq!(start.elapsed(), Duration::from_secs(60));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reinitialize_after_reset() {
+        let (mut c, remote) = duplex_conn();
+        let (_up_tx, mut up_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (down_tx, _down_rx) = crossbeam::channel::unbounded();
+        tokio::spawn(async move { c.event_loop(&mut up_rx, &down_tx, &Timing::default()).await });
+        let (remote_rx, mut remote_tx) = tokio::io::split(remote);
+        remote_tx.write_all(b"1_RDY|1\n").await.unwrap();
+        let mut lines = tokio::io::BufReader::new(remote_rx).lines();
+        let mut sim = crate::sim::Simulator::demo(1);
+        let mut sent = Vec::new();
+        while let Some(line) = lines.next_line().await.unwrap() {
+            let done = line.contains("LISTALL1");
+            // setup fails unless the controller answers
+            for resp in sim.command(&line) {
+                remote_tx.write_all(resp.as_bytes()).await.unwrap();
+                remote_tx.write_all(b"\n").await.unwrap();
+            }
+            sent.push(line);
+            if done {
+                break;
+            }
+        }
+        assert_eq!(sent[0], "SET,SYS,DATAPRINT,1");
+        assert_eq!(
+            &sent[sent.len() - 2..],
+            &["GET,SYS,INFO", "GET,OWB,LISTALL1"]
+        );
+    }
 }
@@ -1,4 +1,4 @@
-use super::{centi2float, AnnounceDevice, Result};
+use super::{availability, centi2float, AnnounceDevice, Result};
 use crate::parser::{Msg, OW};
 use crate::{Device, DeviceInfo, MqttMsg, TwoWay};
 use serde_json::json;
@@ -14,13 +14,14 @@ fn mkann(
 ) -> MqttMsg {
     let info = this.info();
     let name = format!("{} {}", this.name(), name);
-    MqttMsg::retain(
+    MqttMsg::discovery(
         format!(
-            "homeassistant/sensor/{}/{}_{}/config",
-            info.contno, info.serno, short
+            "{}/sensor/{}/{}_{}/config",
+            info.prefix.discovery, info.contno, info.serno, short
         ),
         serde_json::to_string(&json!({
-            "availability_topic": info.status_topic(),
+            "availability": availability(info),
+            "availability_mode": "all",
             "device": &dev,
             "device_class": class,
             "expire_after": 600,
@@ -130,10 +131,14 @@ impl Device for Temperature {
     fn announce(&self) -> Vec<MqttMsg> {
         let dev = self.announce_device();
         let info = self.info();
-        vec![MqttMsg::retain(
-            format!("homeassistant/sensor/{}/{}/config", info.contno, info.serno),
+        vec![MqttMsg::discovery(
+            format!(
+                "{}/sensor/{}/{}/config",
+                info.prefix.discovery, info.contno, info.serno
+            ),
             serde_json::to_string(&json!({
-                        "availability_topic": info.status_topic(),
+                        "availability": availability(info),
+                        "availability_mode": "all",
                         "device": &dev,
                         "device_class": "temperature",
                         "expire_after": 600,
@@ -1,4 +1,5 @@
-use super::{digital_io, disc_topic, Result};
+use super::{availability, digital_io, disc_topic, Result};
+use crate::config::DeviceConf;
 use crate::parser::{Msg, OW};
 use crate::{Device, DeviceInfo, MqttMsg, TwoWay};
 
@@ -7,6 +8,7 @@ use serde_json::json;
 #[derive(Debug, Clone, PartialEq, Default)]
 pub struct BinarySensor {
     info: DeviceInfo,
+    bitstrings: bool,
 }
 
 impl BinarySensor {
@@ -16,8 +18,12 @@ impl BinarySensor {
 impl Device for BinarySensor {
     std_methods!(BinarySensor);
 
+    fn configure(&mut self, conf: &DeviceConf) {
+        self.bitstrings = conf.bitstrings;
+    }
+
     fn register_1wire(&self) -> Vec<String> {
-        self.info.mkbusaddrs(&[1])
+        self.info.mkbusaddrs(&[if self.bitstrings { 2 } else { 1 }])
     }
 
     fn handle_1wire(&mut self, resp: OW) -> Result<TwoWay> {
@@ -25,7 +31,7 @@ impl Device for BinarySensor {
             Msg::Devstatus(s) => {
                 debug!("[{}] BinarySensor {} is {:b}", resp.contno, s.addr, s.val);
                 match s.addr.rsplit('_').next().unwrap() {
-                    "1" => digital_io(&self.info, 8, "in", s.val, None),
+                    "1" | "2" => digital_io(&self.info, 8, "in", s.val, None),
                     other => panic!("BUG: Unknown busaddr {}", other),
                 }
             }
@@ -44,10 +50,11 @@ impl Device for BinarySensor {
         (1..=8)
             .map({
                 |ch| {
-                    MqttMsg::retain(
+                    MqttMsg::discovery(
                         disc_topic("binary_sensor", &self.info, format_args!("ch{}", ch)),
                         serde_json::to_string(&json!({
-                            "availability_topic": self.info.status_topic(),
+                            "availability": availability(&self.info),
+                            "availability_mode": "all",
                             "device": &dev,
                             "expire_after": 300,
                             "name": format!("In {}/{}.{}", self.info.contno, self.name(), ch),
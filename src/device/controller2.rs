@@ -1,5 +1,9 @@
-use super::{centi2float, digital_io, disc_topic, float2centi, str2bool, Error, Result, Token};
-use crate::parser::{Msg, DIO, OW};
+use super::{
+    availability, centi2float, digital_io, disc_topic, float2centi, str2bool, Error, Result, Token,
+};
+use crate::config::DeviceConf;
+use crate::parser::{Command, Msg, Section, DIO, OW};
+use crate::state::Outputs;
 use crate::{Device, DeviceInfo, MqttMsg, TwoWay};
 
 use serde_json::json;
@@ -10,6 +14,9 @@ pub struct Controller2 {
     dio: DIO,
     sw_version: String,
     inputs: i32,
+    outputs: Option<i32>,
+    ana: Option<i32>,
+    bitstrings: bool,
 }
 
 impl Controller2 {
@@ -19,19 +26,28 @@ impl Controller2 {
 impl Device for Controller2 {
     std_methods!(Controller2);
 
-    fn init(&mut self) -> Vec<String> {
-        vec!["SET,SYS,OUTA,500".into(), "GET,SYS,DIO".into()]
+    fn init(&mut self) -> Vec<Command> {
+        vec![Command::SysAna(500), Command::get(Section::SYS, "DIO")]
+    }
+
+    fn configure(&mut self, conf: &DeviceConf) {
+        self.bitstrings = conf.bitstrings;
     }
 
     fn register_1wire(&self) -> Vec<String> {
-        vec!["SYS1_1".into(), "SYS2_1".into(), "SYS3".into()]
+        if self.bitstrings {
+            vec!["SYS1_2".into(), "SYS2_2".into(), "SYS3".into()]
+        } else {
+            vec!["SYS1_1".into(), "SYS2_1".into(), "SYS3".into()]
+        }
     }
 
     fn handle_1wire(&mut self, resp: OW) -> Result<TwoWay> {
         Ok(match resp.msg {
             Msg::CSI(csi) => {
+                // announcement follows once the bus has been populated
                 self.sw_version = csi.fw;
-                TwoWay::mqtt(self.announce())
+                TwoWay::default()
             }
             Msg::DIO(dio) => {
                 debug!("[{}] DIO status: {}", resp.contno, dio);
@@ -41,14 +57,20 @@ impl Device for Controller2 {
             Msg::Devstatus(s) => {
                 debug!("[{}] Controller2 {} => {:b}", resp.contno, s.addr, s.val);
                 match s.addr.as_ref() {
-                    "SYS1_1" => {
+                    "SYS1_1" | "SYS1_2" => {
                         let res = digital_io(&self.info, 4, "in", s.val, None)
                             + digital_io(&self.info, 4, "button", s.val, Some(self.inputs));
                         self.inputs = s.val;
                         res
                     }
-                    "SYS2_1" => digital_io(&self.info, 5, "out", s.val, None),
-                    "SYS3" => TwoWay::from_mqtt(self.info.mqtt_msg("out/ana", centi2float(s.val))),
+                    "SYS2_1" | "SYS2_2" => {
+                        self.outputs = Some(s.val);
+                        digital_io(&self.info, 5, "out", s.val, None)
+                    }
+                    "SYS3" => {
+                        self.ana = Some(s.val);
+                        TwoWay::from_mqtt(self.info.mqtt_msg("out/ana", centi2float(s.val)))
+                    }
                     other => panic!("BUG: Unknown busaddr {}", other),
                 }
             }
@@ -62,16 +84,40 @@ impl Device for Controller2 {
         })
     }
 
+    fn outputs(&self) -> Outputs {
+        let mut res = Outputs::new();
+        if let Some(out) = self.outputs {
+            res.extend((1..=5).map(|i| (format!("ch{}", i), (out >> (i - 1)) & 1)));
+        }
+        if let Some(ana) = self.ana {
+            res.insert("ana".into(), ana);
+        }
+        res
+    }
+
+    fn restore(&self, outputs: &Outputs) -> Vec<Command> {
+        let mut cmds: Vec<Command> = (1..=5)
+            .map(|i| Command::SysOut {
+                ch: i,
+                on: outputs.get(&format!("ch{}", i)).copied().unwrap_or(0) != 0,
+            })
+            .collect();
+        let ana = outputs.get("ana").copied().unwrap_or(0);
+        cmds.push(Command::SysAna(ana.clamp(0, 1000) as u16));
+        cmds
+    }
+
     fn announce(&self) -> Vec<MqttMsg> {
         let mut dev = self.announce_device();
         dev.sw_version = Some(self.sw_version.clone());
         dev.via_device = None;
         let mut res = Vec::with_capacity(20);
         let binary_sensor = |ch| {
-            MqttMsg::retain(
+            MqttMsg::discovery(
                 disc_topic("binary_sensor", &self.info, format_args!("button_{}", ch)),
                 serde_json::to_string(&json!({
-                        "availability_topic": self.info.status_topic(),
+                        "availability": availability(&self.info),
+                        "availability_mode": "all",
                         "state_topic": self.info.fmt(format_args!("in/ch{}", ch)),
                         "device": &dev,
                         "name": format!("Controller.{} in {}", self.info.contno, ch),
@@ -93,10 +139,11 @@ impl Device for Controller2 {
             res.push(binary_sensor(ch));
         }
         for ch in 1..=5 {
-            res.push(MqttMsg::retain(
+            res.push(MqttMsg::discovery(
                 disc_topic("switch", &self.info, format_args!("ch{}", ch)),
                 serde_json::to_string(&json!({
-                        "availability_topic": self.info.status_topic(),
+                        "availability": availability(&self.info),
+                        "availability_mode": "all",
                         "command_topic": self.info.fmt(format_args!("set/ch{}", ch)),
                         "state_topic": self.info.fmt(format_args!("out/ch{}", ch)),
                         "device": &dev,
@@ -109,10 +156,11 @@ impl Device for Controller2 {
                 .unwrap(),
             ));
         }
-        res.push(MqttMsg::new(
+        res.push(MqttMsg::discovery(
             disc_topic("light", &self.info, format_args!("ana")),
             serde_json::to_string(&json!({
-                    "availability_topic": self.info.status_topic(),
+                    "availability": availability(&self.info),
+                    "availability_mode": "all",
                     "brightness_command_topic": self.info.topic("set/ana"),
                     "brightness_state_topic": self.info.topic("out/ana"),
                     "brightness_scale": 10.0,
@@ -136,16 +184,25 @@ impl Device for Controller2 {
         t
     }
 
-    fn handle_mqtt(&self, msg: &MqttMsg, token: Token) -> Result<TwoWay> {
+    fn handle_mqtt(&mut self, msg: &MqttMsg, token: Token) -> Result<TwoWay> {
         let pl = msg.payload();
         Ok(match token {
-            i @ 1..=5 => TwoWay::from_1wire(format!("SET,SYS,OUT,{},{}", i, str2bool(pl) as u8)),
+            i @ 1..=5 => {
+                let val = str2bool(pl);
+                let out = self.outputs.unwrap_or(0) & !(1 << (i - 1));
+                self.outputs = Some(out | (val as i32) << (i - 1));
+                TwoWay::from_1wire(Command::SysOut {
+                    ch: i as u8,
+                    on: val,
+                })
+            }
             6 => {
                 let val: f32 = pl.parse().map_err(|_| Error::Value(pl.into()))?;
                 if !(0.0..=10.0).contains(&val) {
                     return Err(Error::Value(pl.into()));
                 } else {
-                    TwoWay::from_1wire(format!("SET,SYS,OUTA,{}", float2centi(val)))
+                    self.ana = Some(float2centi(val));
+                    TwoWay::from_1wire(Command::SysAna(float2centi(val) as u16))
                 }
             }
             _ => TwoWay::default(),
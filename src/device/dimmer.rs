@@ -1,5 +1,6 @@
-use super::{bool2str, disc_topic, Error, Result, Token};
-use crate::parser::{Msg, OW};
+use super::{availability, bool2str, disc_topic, Error, Result, Token};
+use crate::parser::{Command, Msg, OW};
+use crate::state::Outputs;
 use crate::{Device, DeviceInfo, MqttMsg, TwoWay};
 
 use serde_json::json;
@@ -7,6 +8,7 @@ use serde_json::json;
 #[derive(Debug, Clone, PartialEq, Default)]
 pub struct Dimmer {
     info: DeviceInfo,
+    levels: [Option<i32>; 2],
 }
 
 impl Dimmer {
@@ -44,6 +46,7 @@ impl Device for Dimmer {
                     }
                     "3" | "4" => {
                         let ch = func.parse::<u8>().unwrap() - 2;
+                        self.levels[ch as usize - 1] = Some(s.val);
                         debug!(
                             "[{}] Dimmer {} channel{}={}",
                             ow.contno,
@@ -76,16 +79,38 @@ impl Device for Dimmer {
         Ok(res)
     }
 
+    fn outputs(&self) -> Outputs {
+        self.levels
+            .iter()
+            .enumerate()
+            .filter_map(|(i, level)| level.map(|l| (format!("ch{}", i + 1), l)))
+            .collect()
+    }
+
+    /// Channels without a known level are left alone.
+    fn restore(&self, outputs: &Outputs) -> Vec<Command> {
+        (1..=2)
+            .filter_map(|ch| {
+                outputs.get(&format!("ch{}", ch)).map(|level| Command::Dim {
+                    devno: self.info.owd(),
+                    ch,
+                    level: (*level).clamp(0, 31) as u8,
+                })
+            })
+            .collect()
+    }
+
     fn announce(&self) -> Vec<MqttMsg> {
         let mut res = Vec::new();
         let dev = self.announce_device();
         for ch in &[1, 2] {
             res.push(self.announce_trigger(&dev, *ch, "short", "0"));
             res.push(self.announce_trigger(&dev, *ch, "short", "1"));
-            res.push(MqttMsg::retain(
+            res.push(MqttMsg::discovery(
                 disc_topic("light", &self.info, format_args!("ch{}", ch)),
                 serde_json::to_string(&json!({
-                    "availability_topic": self.info.status_topic(),
+                    "availability": availability(&self.info),
+                    "availability_mode": "all",
                     "brightness_command_topic": self.info.fmt(format_args!("set/ch{}", ch)),
                     "command_topic": self.info.fmt(format_args!("set/ch{}", ch)),
                     "brightness_scale": 31,
@@ -113,7 +138,7 @@ impl Device for Dimmer {
         ]
     }
 
-    fn handle_mqtt(&self, msg: &MqttMsg, token: Token) -> Result<TwoWay> {
+    fn handle_mqtt(&mut self, msg: &MqttMsg, token: Token) -> Result<TwoWay> {
         let val: u8 = msg
             .payload()
             .parse()
@@ -127,12 +152,12 @@ impl Device for Dimmer {
         );
         match (token, val) {
             (ch, val) if (1..=2).contains(&ch) && (0..32).contains(&val) => {
-                return Ok(TwoWay::from_1wire(format!(
-                    "SET,OWD,DIM,{},{},{}",
-                    self.info.devno(),
-                    ch,
-                    val
-                )))
+                self.levels[ch as usize - 1] = Some(val as i32);
+                return Ok(TwoWay::from_1wire(Command::Dim {
+                    devno: self.info.owd(),
+                    ch: ch as u8,
+                    level: val,
+                }));
             }
             _ => warn!(
                 "[{}] Dimmer {}: invalid MQTT message {:?}",
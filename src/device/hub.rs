@@ -1,4 +1,4 @@
-use super::{centi2float, disc_topic, Result};
+use super::{availability, centi2float, disc_topic, Result};
 use crate::parser::{Msg, OW};
 use crate::{Device, DeviceInfo, MqttMsg, TwoWay};
 use serde_json::json;
@@ -28,10 +28,11 @@ impl Device for Hub {
         for voltage in &[12, 5] {
             for (name, measure) in &[("cur", "current"), ("vdd", "voltage")] {
                 let topic = format!("{}_{}", name, voltage);
-                res.push(MqttMsg::retain(
+                res.push(MqttMsg::discovery(
                     disc_topic("sensor", &self.info, format_args!("{}", topic)),
                     serde_json::to_string(&json!({
-                        "availability_topic": self.info.status_topic(),
+                        "availability": availability(&self.info),
+                        "availability_mode": "all",
                         "device_class": measure,
                         "device": &dev,
                         "expire_after": 300,
@@ -1,5 +1,7 @@
+use crate::config::DeviceConf;
 use crate::parser::OW;
-use crate::{DeviceInfo, MqttMsg, Token, TwoWay};
+use crate::state::Outputs;
+use crate::{Command, DeviceInfo, MqttMsg, Token, TwoWay};
 
 use enum_dispatch::enum_dispatch;
 use serde::Serialize;
@@ -51,13 +53,28 @@ pub trait Device {
         true
     }
 
+    /// Applies device-specific settings from the configuration file.
+    fn configure(&mut self, _conf: &DeviceConf) {}
+
     /// Initializes device. This involved setting custom struct fields or issueing commands to the
     /// 1-Wire device. 1-Wire responses to initialization commands must be processed via
     /// [`handle_1wire`].
-    fn init(&mut self) -> Vec<String> {
+    fn init(&mut self) -> Vec<Command> {
         Vec::default()
     }
 
+    /// Returns current output states. Devices without outputs return an empty map.
+    fn outputs(&self) -> Outputs {
+        Outputs::new()
+    }
+
+    /// Returns commands which set outputs to the given states, e.g. after a controller reset.
+    /// Outputs not mentioned are switched off unless the device has no sensible default for
+    /// them, in which case they are left alone.
+    fn restore(&self, _outputs: &Outputs) -> Vec<Command> {
+        Vec::new()
+    }
+
     /// Announces device discovery data via MQTT.
     fn announce(&self) -> Vec<MqttMsg> {
         vec![]
@@ -94,7 +111,7 @@ pub trait Device {
             "1" => "press",
             _ => panic!("BUG: invalid button payload"),
         };
-        MqttMsg::retain(
+        MqttMsg::discovery(
             disc_topic(
                 "device_automation",
                 info,
@@ -112,6 +129,27 @@ pub trait Device {
         )
     }
 
+    /// Diagnostic sensor for the device's 1-Wire error counter. The controller's counter covers
+    /// the whole bus.
+    fn announce_errors(&self) -> MqttMsg {
+        let info = self.info();
+        MqttMsg::discovery(
+            disc_topic("sensor", info, format_args!("errors")),
+            serde_json::to_string(&json!({
+                "availability": availability(info),
+                "availability_mode": "all",
+                "device": self.announce_device(),
+                "entity_category": "diagnostic",
+                "icon": "mdi:alert-circle-outline",
+                "name": format!("1-Wire errors {}/{}", info.contno, self.name()),
+                "state_class": "total_increasing",
+                "state_topic": info.topic("errors"),
+                "unique_id": format!("{}_errors", info.serno),
+            }))
+            .unwrap(),
+        )
+    }
+
     /// Returns list of 1-Wire busaddrs (e.g., OWD14_1) for which events should be routed to this
     /// component.
     fn register_1wire(&self) -> Vec<String> {
@@ -129,7 +167,7 @@ pub trait Device {
         Vec::default()
     }
 
-    fn handle_mqtt(&self, _msg: &MqttMsg, _token: Token) -> Result<TwoWay> {
+    fn handle_mqtt(&mut self, _msg: &MqttMsg, _token: Token) -> Result<TwoWay> {
         Ok(TwoWay::default())
     }
 }
@@ -211,9 +249,39 @@ fn centi2float(c: i32) -> f32 {
     (c as f32) / 100.
 }
 
+/// Availability topics for discovery announcements. Use together with `"availability_mode":
+/// "all"` so that a device is considered available only if the bridge, the controller it is
+/// attached to and the device itself are online.
+///
+/// Home Assistant ignores payloads other than `payload_available`/`payload_not_available`, so
+/// additional states are mapped by templates: device errors (`error_N`) count as offline, a
+/// `degraded` controller is still considered online.
+fn availability(info: &DeviceInfo) -> serde_json::Value {
+    let entry = |topic: String, template: &str| {
+        json!({
+            "topic": topic,
+            "payload_available": "online",
+            "payload_not_available": "offline",
+            "value_template": template,
+        })
+    };
+    json!([
+        entry(info.prefix.status_topic(), "{{ value }}"),
+        entry(
+            info.ctrl_status(),
+            "{{ 'offline' if value == 'offline' else 'online' }}"
+        ),
+        entry(
+            info.status_topic(),
+            "{{ 'online' if value == 'online' else 'offline' }}"
+        ),
+    ])
+}
+
 fn disc_topic(typ: &str, info: &DeviceInfo, sub: fmt::Arguments) -> String {
     format!(
-        "homeassistant/{}/{}/{}_{}/config",
+        "{}/{}/{}/{}_{}/config",
+        info.prefix.discovery,
         typ,
         info.contno,
         info.serno.replace(
@@ -269,6 +337,20 @@ fn digital_io(
 mod test {
     use super::*;
 
+    #[test]
+    fn availability_maps_error_states() {
+        let info = DeviceInfo::new(1, "OWD2", "", "online", "", Some("K1")).unwrap();
+        let avail = availability(&info);
+        assert_eq!(avail[2]["topic"], "ESERA/1/K1/status");
+        assert_eq!(avail[2]["payload_available"], "online");
+        assert_eq!(
+            avail[2]["value_template"],
+            "{{ 'online' if value == 'online' else 'offline' }}"
+        );
+        assert_eq!(avail[1]["topic"], "ESERA/1/status");
+        assert_eq!(avail[0]["payload_not_available"], "offline");
+    }
+
     #[test]
     fn digio_mqtt() {
         assert_eq!(
@@ -410,7 +492,7 @@ impl Device for Unknown {
         Vec::default()
     }
 
-    fn handle_mqtt(&self, _msg: &MqttMsg, _token: Token) -> Result<TwoWay> {
+    fn handle_mqtt(&mut self, _msg: &MqttMsg, _token: Token) -> Result<TwoWay> {
         Ok(TwoWay::default())
     }
 }
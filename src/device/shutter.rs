@@ -1,13 +1,18 @@
-use super::{digital_io, Device, DeviceInfo, MqttMsg, Result, Token, TwoWay};
-use crate::parser::{Msg, OW};
+use super::{availability, digital_io, Device, DeviceInfo, MqttMsg, Result, Token, TwoWay};
+use crate::config::DeviceConf;
+use crate::parser::{Command, Move, Msg, OW};
+use crate::state::Outputs;
 
 use serde_json::json;
 use std::time::Instant;
 
 const DEF_TIME: f32 = 60.0;
 
-#[derive(Debug, Eq, PartialEq, Clone, Copy, strum_macros::IntoStaticStr, strum_macros::Display)]
+#[derive(
+    Debug, Default, Eq, PartialEq, Clone, Copy, strum_macros::IntoStaticStr, strum_macros::Display,
+)]
 enum Direction {
+    #[default]
     #[strum(serialize = "STOP")]
     Stop = 0,
     #[strum(serialize = "CLOSE")]
@@ -18,12 +23,6 @@ enum Direction {
 
 use Direction::*;
 
-impl Default for Direction {
-    fn default() -> Self {
-        Stop
-    }
-}
-
 #[derive(Debug, Clone, PartialEq, Default)]
 pub struct Shutter {
     info: DeviceInfo,
@@ -32,6 +31,11 @@ pub struct Shutter {
     start: Option<Instant>,
     initial_pos: f32,
     position: f32,
+    close_time: Option<f32>,
+    open_time: Option<f32>,
+    /// Last commanded position (0 = closed, 100 = open)
+    target: Option<i32>,
+    bitstrings: bool,
 }
 
 fn clamp(val: f32, min: f32, max: f32) -> f32 {
@@ -48,7 +52,17 @@ impl Shutter {
         }
     }
 
+    /// Time needed to fully close/open. Configured values take precedence over the legacy
+    /// `SHUTTER_<CONTNO>_<NAME>_<CLOSE|OPEN>_TIME` environment variables.
     fn time_to(&self, what: Direction) -> f32 {
+        let configured = match what {
+            Close => self.close_time,
+            Open => self.open_time,
+            Stop => None,
+        };
+        if let Some(t) = configured {
+            return t;
+        }
         match std::env::var(format!(
             "SHUTTER_{}_{}_{}_TIME",
             self.info.contno,
@@ -124,14 +138,24 @@ impl Shutter {
 impl Device for Shutter {
     std_methods!(Shutter);
 
+    fn configure(&mut self, conf: &DeviceConf) {
+        self.close_time = conf.close_time;
+        self.open_time = conf.open_time;
+        self.bitstrings = conf.bitstrings;
+    }
+
     fn register_1wire(&self) -> Vec<String> {
-        self.info.mkbusaddrs(&[1, 3])
+        if self.bitstrings {
+            self.info.mkbusaddrs(&[2, 4])
+        } else {
+            self.info.mkbusaddrs(&[1, 3])
+        }
     }
 
     fn handle_1wire(&mut self, ow: OW) -> Result<TwoWay> {
         Ok(match ow.msg {
             Msg::Devstatus(s) => match s.subaddr() {
-                Some(1) => {
+                Some(1) | Some(2) => {
                     debug!(
                         "[{}] Shutter {} buttons={:02b}",
                         ow.contno,
@@ -143,7 +167,7 @@ impl Device for Shutter {
                     self.buttons = s.val;
                     res
                 }
-                Some(3) => {
+                Some(3) | Some(4) => {
                     debug!(
                         "[{}] Shutter {} state={:02b}",
                         ow.contno,
@@ -211,9 +235,10 @@ impl Device for Shutter {
             res.push(self.announce_trigger(&dev, *button, "short", "0"));
             res.push(self.announce_trigger(&dev, *button, "short", "1"));
         }
-        res.push(MqttMsg::retain(
+        res.push(MqttMsg::discovery(
             format!(
-                "homeassistant/cover/{}/{}/config",
+                "{}/cover/{}/{}/config",
+                i.prefix.discovery,
                 i.contno,
                 i.serno.replace(
                     |c: char| { !c.is_ascii_alphanumeric() && c != '_' && c != '-' },
@@ -221,7 +246,8 @@ impl Device for Shutter {
                 )
             ),
             serde_json::to_string(&json!({
-                "availability_topic": i.status_topic(),
+                "availability": availability(i),
+                "availability_mode": "all",
                 "command_topic": i.topic("set"),
                 "device": dev,
                 "name": format!("Shutter {}/{}", i.contno, self.name()),
@@ -240,11 +266,34 @@ impl Device for Shutter {
         res
     }
 
+    fn outputs(&self) -> Outputs {
+        self.target
+            .map(|pos| Outputs::from([("position".to_string(), pos)]))
+            .unwrap_or_default()
+    }
+
+    /// Only fully closed or open positions can be restored since the controller has no command
+    /// to move to an intermediate position.
+    fn restore(&self, outputs: &Outputs) -> Vec<Command> {
+        let devno = self.info.owd();
+        match outputs.get("position") {
+            Some(0) => vec![Command::Shutter {
+                devno,
+                op: Move::Close,
+            }],
+            Some(100) => vec![Command::Shutter {
+                devno,
+                op: Move::Open,
+            }],
+            _ => Vec::new(),
+        }
+    }
+
     fn register_mqtt(&self) -> Vec<(String, Token)> {
         vec![(self.info.topic("set"), 0)]
     }
 
-    fn handle_mqtt(&self, msg: &MqttMsg, _: Token) -> Result<TwoWay> {
+    fn handle_mqtt(&mut self, msg: &MqttMsg, _: Token) -> Result<TwoWay> {
         let pl = msg.payload();
         debug!(
             "[{}] Shutter {}: MQTT: set {}",
@@ -252,11 +301,20 @@ impl Device for Shutter {
             self.name(),
             pl
         );
-        let d = self.info.devno();
-        Ok(match pl {
-            "CLOSE" => TwoWay::from_1wire(format!("SET,OWD,SHT,{},1", d)),
-            "OPEN" => TwoWay::from_1wire(format!("SET,OWD,SHT,{},2", d)),
-            "STOP" => TwoWay::from_1wire(format!("SET,OWD,SHT,{},3", d)),
+        let op = match pl {
+            "CLOSE" => {
+                self.target = Some(0);
+                Move::Close
+            }
+            "OPEN" => {
+                self.target = Some(100);
+                Move::Open
+            }
+            "STOP" => {
+                self.calc();
+                self.target = Some(self.position.round() as i32);
+                Move::Stop
+            }
             _ => {
                 error!(
                     "[{}] Shutter {}: unrecognized MQTT command {}",
@@ -264,8 +322,12 @@ impl Device for Shutter {
                     self.name(),
                     pl
                 );
-                TwoWay::default()
+                return Ok(TwoWay::default());
             }
-        })
+        };
+        Ok(TwoWay::from_1wire(Command::Shutter {
+            devno: self.info.owd(),
+            op,
+        }))
     }
 }
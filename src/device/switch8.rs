@@ -1,14 +1,17 @@
-use super::{digital_io, disc_topic, str2bool, AnnounceDevice, Result, Token};
-use crate::parser::{Msg, OW};
+use super::{availability, digital_io, disc_topic, str2bool, AnnounceDevice, Result, Token};
+use crate::config::DeviceConf;
+use crate::parser::{Command, Msg, OW};
+use crate::state::Outputs;
 use crate::{Device, DeviceInfo, MqttMsg, TwoWay};
 
 use serde_json::json;
 
 fn ann_out_ch(dev: &AnnounceDevice, name: &str, info: &DeviceInfo, ch: u8) -> MqttMsg {
-    MqttMsg::retain(
-        disc_topic("switch", &info, format_args!("ch{}", ch)),
+    MqttMsg::discovery(
+        disc_topic("switch", info, format_args!("ch{}", ch)),
         serde_json::to_string(&json!({
-                "availability_topic": info.status_topic(),
+                "availability": availability(info),
+                "availability_mode": "all",
                 "command_topic": info.fmt(format_args!("set/ch{}", ch)),
                 "state_topic": info.fmt(format_args!("out/ch{}", ch)),
                 "device": dev,
@@ -26,6 +29,8 @@ fn ann_out_ch(dev: &AnnounceDevice, name: &str, info: &DeviceInfo, ch: u8) -> Mq
 pub struct Switch8 {
     info: DeviceInfo,
     inputs: i32,
+    outputs: Option<i32>,
+    bitstrings: bool,
 }
 
 impl Switch8 {
@@ -35,14 +40,22 @@ impl Switch8 {
 impl Device for Switch8 {
     std_methods!(Switch8);
 
+    fn configure(&mut self, conf: &DeviceConf) {
+        self.bitstrings = conf.bitstrings;
+    }
+
     fn register_1wire(&self) -> Vec<String> {
-        self.info.mkbusaddrs(&[1, 3])
+        if self.bitstrings {
+            self.info.mkbusaddrs(&[2, 4])
+        } else {
+            self.info.mkbusaddrs(&[1, 3])
+        }
     }
 
     fn handle_1wire(&mut self, resp: OW) -> Result<TwoWay> {
         Ok(match resp.msg {
             Msg::Devstatus(s) => match s.subaddr() {
-                Some(1) => {
+                Some(1) | Some(2) => {
                     debug!(
                         "[{}] Switch8 {} inputs={:08b}",
                         resp.contno,
@@ -54,13 +67,14 @@ impl Device for Switch8 {
                     self.inputs = s.val;
                     res
                 }
-                Some(3) => {
+                Some(3) | Some(4) => {
                     debug!(
                         "[{}] Switch8 {} outputs={:08b}",
                         resp.contno,
                         self.name(),
                         s.val
                     );
+                    self.outputs = Some(s.val);
                     digital_io(&self.info, 8, "out", s.val, None)
                 }
                 _ => panic!("BUG: Unknown busaddr {}", s.addr),
@@ -72,6 +86,25 @@ impl Device for Switch8 {
         })
     }
 
+    fn outputs(&self) -> Outputs {
+        match self.outputs {
+            Some(out) => (0..8)
+                .map(|i| (format!("ch{}", i + 1), (out >> i) & 1))
+                .collect(),
+            None => Outputs::new(),
+        }
+    }
+
+    fn restore(&self, outputs: &Outputs) -> Vec<Command> {
+        (0..8)
+            .map(|i| Command::Out {
+                devno: self.info.owd(),
+                ch: i,
+                on: outputs.get(&format!("ch{}", i + 1)).copied().unwrap_or(0) != 0,
+            })
+            .collect()
+    }
+
     fn announce(&self) -> Vec<MqttMsg> {
         let mut res = Vec::with_capacity(20);
         let dev = self.announce_device();
@@ -89,16 +122,20 @@ impl Device for Switch8 {
             .collect()
     }
 
-    fn handle_mqtt(&self, msg: &MqttMsg, token: Token) -> Result<TwoWay> {
+    fn handle_mqtt(&mut self, msg: &MqttMsg, token: Token) -> Result<TwoWay> {
         let pl = msg.payload();
         debug!("[{}] Switch8: handle {}", self.info.contno, pl);
         Ok(match token {
-            i @ 0..=7 => TwoWay::from_1wire(format!(
-                "SET,OWD,OUT,{},{},{}",
-                self.info.devno(),
-                i,
-                str2bool(pl) as u8
-            )),
+            i @ 0..=7 => {
+                let val = str2bool(pl);
+                let out = self.outputs.unwrap_or(0) & !(1 << i);
+                self.outputs = Some(out | (val as i32) << i);
+                TwoWay::from_1wire(Command::Out {
+                    devno: self.info.owd(),
+                    ch: i as u8,
+                    on: val,
+                })
+            }
             _ => {
                 warn!("[{}] Switch8: invalid token {}", self.info.contno, token);
                 TwoWay::default()
@@ -0,0 +1,82 @@
+//! Machine readable description of the devices on a bus, e.g. to compare installations or to
+//! generate documentation.
+use crate::Status;
+
+use serde::Serialize;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Item {
+    pub contno: u8,
+    pub busid: String,
+    pub serno: String,
+    pub artno: String,
+    /// Generic device type like "Switch8"
+    pub model: String,
+    pub name: Option<String>,
+    pub status: Status,
+    /// MQTT topics the device publishes to or listens on
+    pub topics: Vec<String>,
+    /// Home Assistant discovery ids (`<component>/<contno>/<object id>`)
+    pub discovery: Vec<String>,
+}
+
+pub type Inventory = Vec<Item>;
+
+const CSV_HEADER: &str = "contno,busid,serno,artno,model,name,status,topics,discovery";
+
+fn csv_field(s: &str) -> String {
+    if s.contains(&[',', '"', '\n'][..]) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
+
+/// Formats an inventory as CSV with header. Lists are separated by spaces.
+pub fn to_csv(inv: &[Item]) -> String {
+    let mut out = String::from(CSV_HEADER);
+    out.push('\n');
+    for item in inv {
+        let fields = [
+            item.contno.to_string(),
+            item.busid.clone(),
+            item.serno.clone(),
+            item.artno.clone(),
+            item.model.clone(),
+            item.name.clone().unwrap_or_default(),
+            item.status.to_string(),
+            item.topics.join(" "),
+            item.discovery.join(" "),
+        ];
+        let fields: Vec<_> = fields.iter().map(|f| csv_field(f)).collect();
+        out.push_str(&fields.join(","));
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn csv_format() {
+        let inv = vec![Item {
+            contno: 1,
+            busid: "OWD2".into(),
+            serno: "4300001982956429".into(),
+            artno: "11220".into(),
+            model: "Switch8".into(),
+            name: Some("K,1".into()),
+            status: Status::Online,
+            topics: vec!["ESERA/1/K,1/in/ch1".into(), "ESERA/1/K,1/status".into()],
+            discovery: vec![],
+        }];
+        assert_eq!(
+            to_csv(&inv),
+            "contno,busid,serno,artno,model,name,status,topics,discovery\n\
+             1,OWD2,4300001982956429,11220,Switch8,\"K,1\",online,\
+             \"ESERA/1/K,1/in/ch1 ESERA/1/K,1/status\",\n"
+        );
+    }
+}
@@ -1,32 +1,43 @@
+#![allow(clippy::upper_case_acronyms)]
+
 mod bus;
+pub mod capture;
 pub mod climate;
+pub mod config;
 mod controller;
 mod device;
+pub mod inventory;
 mod mqtt;
 mod parser;
 mod routing;
+pub mod sim;
+pub mod state;
 
 pub use bus::Bus;
+pub use config::Config;
 pub use controller::ControllerConnection;
 pub use controller::Error as ControllerError;
+pub use controller::{Reply, Request};
 pub use device::{bool2str, str2bool, AnnounceDevice, Device};
 pub use mqtt::{MqttConnection, MqttMsg};
-pub use parser::{Status, CSI, OW};
+pub use parser::{Command, Move, Msg, Section, Status, CSI, OW};
 pub use routing::{Routes, Token};
 
 #[macro_use]
 extern crate log;
-use crossbeam::channel;
+use serde::Serialize;
 use std::fmt;
 use std::iter;
+use std::sync::Arc;
 use thiserror::Error;
+use tokio::sync::mpsc;
 
 #[derive(Error, Debug)]
 pub enum Error {
     #[error(transparent)]
     MQTT(#[from] mqtt::Error),
     #[error(transparent)]
-    Controller(#[from] channel::SendError<String>),
+    Controller(#[from] mpsc::error::SendError<Request>),
     #[error(transparent)]
     Bus(#[from] bus::Error),
     #[error("No handler found for MQTT message {0:?}")]
@@ -37,7 +48,41 @@ pub enum Error {
 
 type Result<T, E = Error> = std::result::Result<T, E>;
 
-#[derive(Debug, Clone, Default, PartialEq)]
+/// MQTT topic prefixes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Prefix {
+    /// Base of all device topics
+    pub base: String,
+    /// Home Assistant discovery prefix
+    pub discovery: String,
+}
+
+impl Prefix {
+    pub fn new<B: Into<String>, D: Into<String>>(base: B, discovery: D) -> Self {
+        Self {
+            base: base.into().trim_end_matches('/').into(),
+            discovery: discovery.into().trim_end_matches('/').into(),
+        }
+    }
+
+    /// Bridge-wide online status. Set to `offline` via last will if the bridge dies.
+    pub fn status_topic(&self) -> String {
+        format!("{}/status", self.base)
+    }
+
+    /// Home Assistant publishes `online` here after (re-)starting.
+    pub fn birth_topic(&self) -> String {
+        format!("{}/status", self.discovery)
+    }
+}
+
+impl Default for Prefix {
+    fn default() -> Self {
+        Self::new("ESERA", "homeassistant")
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
 pub struct DeviceInfo {
     pub contno: u8,
     pub busid: String,
@@ -45,6 +90,8 @@ pub struct DeviceInfo {
     pub status: Status,
     pub artno: String,
     pub name: Option<String>,
+    #[serde(skip)]
+    pub prefix: Arc<Prefix>,
 }
 
 impl DeviceInfo {
@@ -62,6 +109,7 @@ impl DeviceInfo {
             serno: String::from(serno),
             status: status.parse()?,
             artno: String::from(artno),
+            prefix: Arc::default(),
             name: name.and_then(|s| {
                 let n = s.trim();
                 if !n.is_empty() {
@@ -76,7 +124,8 @@ impl DeviceInfo {
     /// Format MQTT message topic relating to this device
     fn fmt(&self, args: fmt::Arguments) -> String {
         format!(
-            "ESERA/{}/{}/{}",
+            "{}/{}/{}/{}",
+            self.prefix.base,
             self.contno,
             self.name.as_ref().unwrap_or(&self.busid),
             args
@@ -88,13 +137,24 @@ impl DeviceInfo {
         self.fmt(format_args!("{}", item.as_ref()))
     }
 
+    /// Per-device availability (online/offline/error_N)
     pub fn status_topic(&self) -> String {
-        format!("ESERA/{}/status", self.contno)
+        self.topic("status")
+    }
+
+    /// Failed commands are reported here
+    pub fn error_topic(&self) -> String {
+        self.topic("error")
+    }
+
+    /// Retained availability message reflecting the current device status
+    pub fn status_msg(&self) -> MqttMsg {
+        MqttMsg::retain(self.status_topic(), self.status)
     }
 
     /// Status topic of the associated controller
     pub fn ctrl_status(&self) -> String {
-        format!("ESERA/{}/status", self.contno)
+        format!("{}/{}/status", self.prefix.base, self.contno)
     }
 
     pub fn mqtt_msg<S: AsRef<str>, P: ToString>(&self, topic: S, value: P) -> MqttMsg {
@@ -115,6 +175,19 @@ impl DeviceInfo {
         self.busid.strip_prefix("OWD").unwrap_or(&self.busid)
     }
 
+    /// Numeric OWD number as used in commands. 0 if this is not a 1-Wire device.
+    pub fn owd(&self) -> u8 {
+        match self.devno().parse() {
+            Ok(n) => n,
+            Err(e) => {
+                if self.busid.starts_with("OWD") {
+                    error!("[{}] Invalid bus id {}: {}", self.contno, self.busid, e);
+                }
+                0
+            }
+        }
+    }
+
     /// Human readable name. Falls back to OWD id if none set.
     pub fn name(&self) -> &str {
         self.name.as_ref().unwrap_or(&self.busid)
@@ -128,24 +201,34 @@ impl DeviceInfo {
 #[derive(Debug, Clone, Default, PartialEq)]
 pub struct TwoWay {
     pub mqtt: Vec<MqttMsg>,
-    pub ow: Vec<String>,
+    /// Controller commands along with the bus slot of the device which issued them (0 for the
+    /// controller itself)
+    pub ow: Vec<(usize, Command)>,
 }
 
 impl TwoWay {
-    pub fn new(msgs: Vec<MqttMsg>, cmds: Vec<String>) -> Self {
+    pub fn new(msgs: Vec<MqttMsg>, cmds: Vec<Command>) -> Self {
         Self {
             mqtt: msgs,
-            ow: cmds,
+            ow: cmds.into_iter().map(|c| (0, c)).collect(),
         }
     }
 
-    pub fn from_1wire<S: Into<String>>(cmd: S) -> Self {
+    pub fn from_1wire(cmd: Command) -> Self {
         Self {
             mqtt: Vec::default(),
-            ow: vec![cmd.into()],
+            ow: vec![(0, cmd)],
         }
     }
 
+    /// Attributes all commands to the device in bus slot `dev`.
+    pub fn issued_by(mut self, dev: usize) -> Self {
+        for (d, _) in &mut self.ow {
+            *d = dev
+        }
+        self
+    }
+
     pub fn from_mqtt(msg: MqttMsg) -> Self {
         Self {
             mqtt: vec![msg],
@@ -160,14 +243,28 @@ impl TwoWay {
         }
     }
 
-    pub fn send(self, mqtt: &mut MqttConnection, ctrl: &channel::Sender<String>) -> Result<()> {
+    /// Publishes MQTT messages and passes commands to the controller. Returns a reply handle for
+    /// each command along with the issuing device's slot. Commands with invalid arguments are
+    /// dropped.
+    pub fn send(
+        self,
+        mqtt: &mut MqttConnection,
+        ctrl: &mpsc::UnboundedSender<Request>,
+    ) -> Result<Vec<(usize, Command, Reply)>> {
         for msg in self.mqtt {
             mqtt.send(msg)?;
         }
-        for cmd in self.ow {
-            ctrl.send(cmd)?;
+        let mut replies = Vec::with_capacity(self.ow.len());
+        for (dev, cmd) in self.ow {
+            if let Err(e) = cmd.validate() {
+                error!("{}", e);
+                continue;
+            }
+            let (req, reply) = Request::new(cmd.clone());
+            ctrl.send(req)?;
+            replies.push((dev, cmd, reply));
         }
-        Ok(())
+        Ok(replies)
     }
 }
 
@@ -224,15 +321,26 @@ mod test {
         }
     }
 
+    #[test]
+    fn custom_prefix() {
+        let mut info = DeviceInfo::new(2, "OWD4", "", "online", "", Some("K1")).unwrap();
+        info.prefix = Arc::new(Prefix::new("site/house1/esera/", "ha"));
+        assert_eq!(info.topic("out/ch1"), "site/house1/esera/2/K1/out/ch1");
+        assert_eq!(info.ctrl_status(), "site/house1/esera/2/status");
+        assert_eq!(info.prefix.status_topic(), "site/house1/esera/status");
+    }
+
     #[test]
     fn add_twoway() {
-        let t1 = TwoWay::new(vec![MqttMsg::new("topic", "msg1")], vec!["CMD1".into()]);
-        let t2 = TwoWay::new(vec![MqttMsg::new("topic", "msg2")], vec!["CMD2".into()]);
+        let cmd1 = Command::get(Section::SYS, "CMD1");
+        let cmd2 = Command::get(Section::SYS, "CMD2");
+        let t1 = TwoWay::new(vec![MqttMsg::new("topic", "msg1")], vec![cmd1.clone()]);
+        let t2 = TwoWay::new(vec![MqttMsg::new("topic", "msg2")], vec![cmd2.clone()]);
         assert_eq!(
             t1 + t2,
             TwoWay::new(
                 vec![MqttMsg::new("topic", "msg1"), MqttMsg::new("topic", "msg2")],
-                vec!["CMD1".into(), "CMD2".into()]
+                vec![cmd1, cmd2]
             )
         );
     }
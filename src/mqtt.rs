@@ -1,8 +1,13 @@
+use crate::config;
+
 use crossbeam::atomic::AtomicCell;
 use crossbeam::channel::{self, Receiver, Sender};
-use rumqttc::{ConnectReturnCode, Event, MqttOptions, Packet, QoS};
+use rumqttc::{ConnectReturnCode, Event, Key, MqttOptions, Packet, QoS, Transport};
 use slog::{debug, error, info, o, warn, Drain, Logger};
 use std::fmt;
+use std::fs;
+use std::io::{self, BufRead};
+use std::path::Path;
 use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
@@ -22,16 +27,35 @@ pub enum Error {
     Utf8(#[from] std::string::FromUtf8Error),
     #[error(transparent)]
     Channel(#[from] channel::SendError<MqttMsg>),
+    #[error("Invalid QoS level {0}")]
+    Qos(u8),
+    #[error("Keepalive interval must be at least 5 seconds (got {0})")]
+    Keepalive(u64),
+    #[error("Cannot read {0}: {1}")]
+    ReadFile(String, #[source] std::io::Error),
+    #[error("No valid {1} found in {0}")]
+    Pem(String, &'static str),
+    #[error("TLS client certificate and key must be given together")]
+    ClientAuth,
 }
 
 type Result<T, E = Error> = std::result::Result<T, E>;
 
+/// Message classification which determines the QoS level used for publishing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    State,
+    Command,
+    Discovery,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum MqttMsg {
     Pub {
         topic: String,
         payload: String,
         retain: bool,
+        kind: Kind,
     },
     Sub {
         topic: String,
@@ -45,6 +69,7 @@ impl MqttMsg {
             topic: topic.into(),
             payload: payload.to_string(),
             retain: false,
+            kind: Kind::State,
         }
     }
 
@@ -53,6 +78,32 @@ impl MqttMsg {
             topic: topic.into(),
             payload: payload.to_string(),
             retain: true,
+            kind: Kind::State,
+        }
+    }
+
+    /// Removes a retained discovery announcement
+    pub fn retract<S: Into<String>>(topic: S) -> Self {
+        Self::discovery(topic, "")
+    }
+
+    /// Retained discovery announcement
+    pub fn discovery<S: Into<String>, P: ToString>(topic: S, payload: P) -> Self {
+        Self::Pub {
+            topic: topic.into(),
+            payload: payload.to_string(),
+            retain: true,
+            kind: Kind::Discovery,
+        }
+    }
+
+    /// Command to be executed by another MQTT participant
+    pub fn command<S: Into<String>, P: ToString>(topic: S, payload: P) -> Self {
+        Self::Pub {
+            topic: topic.into(),
+            payload: payload.to_string(),
+            retain: false,
+            kind: Kind::Command,
         }
     }
 
@@ -103,6 +154,7 @@ impl fmt::Display for MqttMsg {
                 topic,
                 payload,
                 retain,
+                ..
             } => write!(
                 f,
                 "{} {}{}",
@@ -116,9 +168,78 @@ impl fmt::Display for MqttMsg {
     }
 }
 
+#[derive(Debug, Clone, Copy)]
+struct QosLevels {
+    state: QoS,
+    command: QoS,
+    discovery: QoS,
+}
+
+impl QosLevels {
+    fn new(conf: &config::Qos) -> Result<Self> {
+        let level = |n| rumqttc::qos(n).map_err(|_| Error::Qos(n));
+        Ok(Self {
+            state: level(conf.state)?,
+            command: level(conf.command)?,
+            discovery: level(conf.discovery)?,
+        })
+    }
+
+    fn get(&self, kind: Kind) -> QoS {
+        match kind {
+            Kind::State => self.state,
+            Kind::Command => self.command,
+            Kind::Discovery => self.discovery,
+        }
+    }
+}
+
+/// Reads a PEM file and checks that it contains at least one item as recognized by `parse`.
+fn read_pem<T>(
+    path: &Path,
+    what: &'static str,
+    parse: fn(&mut dyn BufRead) -> Result<Vec<T>, ()>,
+) -> Result<Vec<u8>> {
+    let name = path.display().to_string();
+    let pem = fs::read(path).map_err(|e| Error::ReadFile(name.clone(), e))?;
+    match parse(&mut io::Cursor::new(&pem)) {
+        Ok(items) if !items.is_empty() => Ok(pem),
+        _ => Err(Error::Pem(name, what)),
+    }
+}
+
+/// Loads certificates and keys for TLS connections.
+fn tls_transport(conf: &config::Tls) -> Result<Transport> {
+    let ca = read_pem(&conf.ca, "certificate", |r| rumqttc::certs(r))?;
+    let client_auth = match (&conf.client_cert, &conf.client_key) {
+        (Some(cert), Some(key)) => {
+            let cert = read_pem(cert, "certificate", |r| rumqttc::certs(r))?;
+            // Try PKCS#1 RSA keys first and fall back to PKCS#8. Despite its name, rumqttc's
+            // `Key::ECC` only selects the PKCS#8 parser. rustls determines the actual key type
+            // (RSA, ECDSA or Ed25519) from the key itself.
+            let key = match read_pem(key, "private key", |r| rumqttc::rsa_private_keys(r)) {
+                Ok(k) => Key::RSA(k),
+                Err(_) => Key::ECC(read_pem(key, "private key", |r| {
+                    rumqttc::pkcs8_private_keys(r)
+                })?),
+            };
+            Some((cert, key))
+        }
+        (None, None) => None,
+        _ => return Err(Error::ClientAuth),
+    };
+    let alpn = if conf.alpn.is_empty() {
+        None
+    } else {
+        Some(conf.alpn.iter().map(|p| p.as_bytes().to_vec()).collect())
+    };
+    Ok(Transport::tls(ca, client_auth, alpn))
+}
+
 pub struct MqttConnection {
     host: String,
     client: rumqttc::Client,
+    qos: QosLevels,
     shutdown: Arc<AtomicCell<bool>>,
     log: Logger,
 }
@@ -143,20 +264,30 @@ fn process_packet(pck: Packet, tx: &Sender<MqttMsg>, log: &Logger) -> Result<()>
 }
 
 impl MqttConnection {
-    pub fn new<S: Into<String>, T: AsRef<str>, L: Into<Option<Logger>>>(
-        host: S,
-        cred: &str,
+    pub fn new<T: AsRef<str>, L: Into<Option<Logger>>>(
+        conf: &config::Mqtt,
         status_topic: T,
         log: L,
     ) -> Result<(Self, Receiver<MqttMsg>)> {
-        let host = host.into();
+        let host = conf.host.clone();
         // XXX remove StdLog if transition to slog is complete
         let log = log.into().unwrap_or_else(|| {
             Logger::root(slog_stdlog::StdLog.fuse(), o!("host" => host.clone()))
         });
-        let client_id = format!("esera_mqtt.{}", std::process::id());
-        let mut opt = MqttOptions::new(&client_id, &host, 1883);
-        let mut parts = cred.splitn(2, ':');
+        let qos = QosLevels::new(&conf.qos)?;
+        let client_id = conf
+            .client_id
+            .clone()
+            .unwrap_or_else(|| format!("esera_mqtt.{}", std::process::id()));
+        let mut opt = MqttOptions::new(&client_id, &host, conf.port);
+        if conf.keepalive < 5 {
+            return Err(Error::Keepalive(conf.keepalive));
+        }
+        opt.set_keep_alive(Duration::from_secs(conf.keepalive));
+        if let Some(tls) = &conf.tls {
+            opt.set_transport(tls_transport(tls)?);
+        }
+        let mut parts = conf.cred.splitn(2, ':');
         match (parts.next(), parts.next()) {
             (Some(user), Some(pw)) => opt.set_credentials(user, pw),
             (Some(user), None) => opt.set_credentials(user, ""),
@@ -165,7 +296,7 @@ impl MqttConnection {
         opt.set_last_will(rumqttc::LastWill {
             topic: status_topic.as_ref().to_string(),
             message: "offline".into(),
-            qos: QoS::AtMostOnce,
+            qos: qos.state,
             retain: true,
         });
         let (client, mut conn) = rumqttc::Client::new(opt, 100);
@@ -192,6 +323,7 @@ impl MqttConnection {
             let mut this = Self {
                 host,
                 client,
+                qos,
                 shutdown,
                 log,
             };
@@ -216,13 +348,12 @@ impl MqttConnection {
                         return;
                     }
                     match evt {
-                        Ok(Event::Incoming(pck)) => match process_packet(pck, &tx, &log) {
-                            Err(_) => {
+                        Ok(Event::Incoming(pck)) => {
+                            if process_packet(pck, &tx, &log).is_err() {
                                 warn!(log, "MQTT channel disconnected");
                                 return;
                             }
-                            Ok(_) => (),
-                        },
+                        }
                         Ok(Event::Outgoing(_)) => (),
                         Err(e) => {
                             error!(log, "{}, reconnecting in {} ms", e, retry);
@@ -244,10 +375,11 @@ impl MqttConnection {
                 topic,
                 payload,
                 retain,
+                kind,
             } => self
                 .client
-                .publish(topic, QoS::AtMostOnce, retain, payload.as_bytes())?,
-            MqttMsg::Sub { topic } => self.client.subscribe(topic, QoS::AtMostOnce)?,
+                .publish(topic, self.qos.get(kind), retain, payload.as_bytes())?,
+            MqttMsg::Sub { topic } => self.client.subscribe(topic, self.qos.command)?,
             MqttMsg::Reconnected => (), // XXX bail out instead?
         }
         Ok(())
@@ -259,7 +391,7 @@ impl MqttConnection {
 
     pub fn subscribe(&mut self, topic: &str) -> Result<()> {
         self.client
-            .subscribe(topic, QoS::AtMostOnce)
+            .subscribe(topic, self.qos.command)
             .map_err(|e| Error::Subscribe(topic.into(), e))
     }
 }
@@ -275,3 +407,94 @@ impl Drop for MqttConnection {
         self.shutdown.store(true);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tls(cert: Option<&str>, key: Option<&str>) -> config::Tls {
+        let data = |f: &str| Path::new("tests/data/tls").join(f);
+        config::Tls {
+            ca: data("ca.pem"),
+            client_cert: cert.map(data),
+            client_key: key.map(data),
+            alpn: vec!["mqtt".into()],
+        }
+    }
+
+    #[test]
+    fn qos_per_message_kind() {
+        let qos = QosLevels::new(&config::Qos {
+            state: 0,
+            command: 1,
+            discovery: 2,
+        })
+        .unwrap();
+        assert_eq!(qos.get(Kind::State), QoS::AtMostOnce);
+        assert_eq!(qos.get(Kind::Command), QoS::AtLeastOnce);
+        assert_eq!(qos.get(Kind::Discovery), QoS::ExactlyOnce);
+        let kind = |m: MqttMsg| match m {
+            MqttMsg::Pub { kind, .. } => kind,
+            _ => unreachable!(),
+        };
+        assert_eq!(kind(MqttMsg::retain("t", "p")), Kind::State);
+        assert_eq!(kind(MqttMsg::command("t", "p")), Kind::Command);
+        assert_eq!(kind(MqttMsg::retract("t")), Kind::Discovery);
+        assert!(matches!(
+            QosLevels::new(&config::Qos {
+                command: 3,
+                ..Default::default()
+            }),
+            Err(Error::Qos(3))
+        ));
+    }
+
+    #[test]
+    fn tls_with_client_rsa_key() {
+        assert!(matches!(
+            tls_transport(&tls(Some("client.pem"), Some("client.key"))),
+            Ok(Transport::Tls(rumqttc::TlsConfiguration::Simple {
+                client_auth: Some((_, Key::RSA(_))),
+                alpn: Some(_),
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn tls_with_client_pkcs8_key() {
+        assert!(matches!(
+            tls_transport(&tls(Some("client_ec.pem"), Some("client_ec.key"))),
+            Ok(Transport::Tls(rumqttc::TlsConfiguration::Simple {
+                client_auth: Some((_, Key::ECC(_))),
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn tls_with_client_pkcs8_rsa_key() {
+        assert!(matches!(
+            tls_transport(&tls(Some("client.pem"), Some("client_rsa8.key"))),
+            Ok(Transport::Tls(rumqttc::TlsConfiguration::Simple {
+                client_auth: Some((_, Key::ECC(_))),
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn tls_rejects_invalid_files() {
+        assert!(matches!(
+            tls_transport(&tls(Some("client.pem"), None)),
+            Err(Error::ClientAuth)
+        ));
+        assert!(matches!(
+            tls_transport(&tls(Some("client.key"), Some("client.key"))),
+            Err(Error::Pem(_, "certificate"))
+        ));
+        let mut conf = tls(None, None);
+        conf.ca = "tests/data/tls/missing.pem".into();
+        assert!(matches!(tls_transport(&conf), Err(Error::ReadFile(..))));
+    }
+}
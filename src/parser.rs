@@ -1,5 +1,9 @@
 use crate::DeviceInfo;
 
+use serde::Serialize;
+use std::fmt;
+use std::str::FromStr;
+
 use strum_macros::{AsRefStr, Display, EnumDiscriminants, EnumString, IntoStaticStr};
 use thiserror::Error;
 
@@ -13,18 +17,20 @@ pub enum Error {
     Status(#[from] strum::ParseError),
     #[error("Cannot parse numeric argument")]
     ParseInt(#[from] std::num::ParseIntError),
+    #[error("Invalid controller command: {0}")]
+    Command(String),
 }
 
 type Result<T, E = Error> = std::result::Result<T, E>;
 pub type PResult<'i, O> = nom::IResult<&'i str, O, nom::error::VerboseError<&'i str>>;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct OW {
     pub contno: u8,
     pub msg: Msg,
 }
 
-#[derive(Debug, Clone, PartialEq, EnumDiscriminants)]
+#[derive(Debug, Clone, PartialEq, EnumDiscriminants, Serialize)]
 #[strum_discriminants(name(MsgKind))]
 pub enum Msg {
     Keepalive(Keepalive),
@@ -35,6 +41,8 @@ pub enum Msg {
     Rdy(Rdy),
     Save(Save),
     Kalsendtime(Kalsendtime),
+    Kalrec(Kalrec),
+    Kalrectime(Kalrectime),
     Dataprint(Dataprint),
     Datatime(Datatime),
     Date(Date),
@@ -44,6 +52,11 @@ pub enum Msg {
     DIO(DIO),
     OWDStatus(OWDStatus),
     Devstatus(Devstatus),
+    Settings(Settings),
+    ErrSum(ErrSum),
+    ErrOwd(ErrOwd),
+    ErrList(ErrList),
+    OWDName(OWDName),
 }
 
 use nom::branch::alt;
@@ -51,9 +64,9 @@ use nom::bytes::streaming::tag;
 use nom::character::streaming::{
     alphanumeric1, char as cc, digit1, line_ending, not_line_ending, one_of,
 };
-use nom::combinator::{map, map_res, opt, recognize};
-use nom::multi::{many1, many_m_n};
-use nom::sequence::{delimited, pair, preceded, terminated, tuple};
+use nom::combinator::{map, map_res, opt, recognize, verify};
+use nom::multi::{count, many1, many_till};
+use nom::sequence::{delimited, pair, preceded, separated_pair, terminated, tuple};
 
 fn contno(i: &str) -> PResult<'_, u8> {
     map_res(terminated(digit1, cc('_')), |val: &str| val.parse())(i)
@@ -112,6 +125,73 @@ pub fn err(i: &str) -> PResult<'_, OW> {
     )(i)
 }
 
+/// Number of 1-Wire communication errors on the whole bus
+pub type ErrSum = u32;
+
+pub fn errsum(i: &str) -> PResult<'_, OW> {
+    map_res(
+        tuple((header("ERRSUM"), terminated(digit1, line_ending))),
+        |(contno, v)| -> Result<_> {
+            Ok(OW {
+                contno,
+                msg: Msg::ErrSum(v.parse()?),
+            })
+        },
+    )(i)
+}
+
+/// Number of 1-Wire communication errors of a single device
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ErrOwd {
+    pub owd: u8,
+    pub count: u32,
+}
+
+fn errowd_line(i: &str) -> PResult<'_, (u8, ErrOwd)> {
+    map_res(
+        tuple((
+            contno,
+            delimited(tag("ERROWD"), digit1, cc('|')),
+            terminated(digit1, line_ending),
+        )),
+        |(contno, n, v)| -> Result<_> {
+            Ok((
+                contno,
+                ErrOwd {
+                    owd: n.parse()?,
+                    count: v.parse()?,
+                },
+            ))
+        },
+    )(i)
+}
+
+pub fn errowd(i: &str) -> PResult<'_, OW> {
+    map(errowd_line, |(contno, e)| OW {
+        contno,
+        msg: Msg::ErrOwd(e),
+    })(i)
+}
+
+/// Error counters of all devices (`GET,OWB,ERRLISTALL1`). The list header looks like an error
+/// message, but carries a timestamp instead of an error code.
+pub type ErrList = Vec<ErrOwd>;
+
+pub fn errlist(i: &str) -> PResult<'_, OW> {
+    let (i, contno) = terminated(
+        header("ERR"),
+        terminated(verify(timeval, |t: &str| t.contains(':')), line_ending),
+    )(i)?;
+    let (i, items) = many1(map(verify(errowd_line, |(c, _)| *c == contno), |(_, e)| e))(i)?;
+    Ok((
+        i,
+        OW {
+            contno,
+            msg: Msg::ErrList(items),
+        },
+    ))
+}
+
 pub type Evt = String;
 
 pub fn evt(i: &str) -> PResult<'_, OW> {
@@ -171,6 +251,33 @@ pub fn kalsendtime(i: &str) -> PResult<'_, OW> {
     )(i)
 }
 
+pub type Kalrec = char;
+
+pub fn kalrec(i: &str) -> PResult<'_, OW> {
+    map(
+        tuple((header("KALREC"), terminated(one_of("01"), line_ending))),
+        |(contno, flag)| OW {
+            contno,
+            msg: Msg::Kalrec(flag),
+        },
+    )(i)
+}
+
+pub type Kalrectime = u8;
+
+pub fn kalrectime(i: &str) -> PResult<'_, OW> {
+    map(
+        tuple((
+            header("KALRECTIME"),
+            terminated(map_res(digit1, str::parse), line_ending),
+        )),
+        |(contno, t)| OW {
+            contno,
+            msg: Msg::Kalrectime(t),
+        },
+    )(i)
+}
+
 pub type Dataprint = char;
 
 pub fn dataprint(i: &str) -> PResult<'_, OW> {
@@ -222,7 +329,7 @@ pub fn time(i: &str) -> PResult<'_, OW> {
     )(i)
 }
 
-#[derive(Debug, Clone, Default, PartialEq)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
 pub struct CSI {
     pub date: String,
     pub time: String,
@@ -258,11 +365,126 @@ pub fn csi(i: &str) -> PResult<'_, OW> {
     )(i)
 }
 
+/// Controller configuration as reported in response to GET,SYS,SETTING
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Settings {
+    /// Controller uptime when the block was sent
+    pub cse: String,
+    pub debug: u8,
+    pub kalsend: u8,
+    pub kalsendtime: u8,
+    pub kalrec: u8,
+    pub kalrectime: u8,
+    pub kalalarm: Vec<u8>,
+    pub datasend: u8,
+    pub datatime: u8,
+    pub dataformat: u8,
+    pub search: u8,
+    pub searchtime: u8,
+    pub format: u8,
+    pub count: u8,
+    pub ds2408inv: u8,
+    pub owdid: u8,
+    pub polltime: u8,
+    pub owdidformat: u8,
+    pub autoecon: u8,
+    pub dio: u8,
+}
+
+impl Settings {
+    fn set(&mut self, key: &str, val: &str) -> Result<()> {
+        let field = match key {
+            "KALALARM" => {
+                self.kalalarm = val.split('|').map(str::parse).collect::<Result<_, _>>()?;
+                return Ok(());
+            }
+            "DEBUG" => &mut self.debug,
+            "KALSEND" => &mut self.kalsend,
+            "KALSENDTIME" => &mut self.kalsendtime,
+            "KALREC" => &mut self.kalrec,
+            "KALRECTIME" => &mut self.kalrectime,
+            "DATASEND" => &mut self.datasend,
+            "DATATIME" => &mut self.datatime,
+            "DATAFORMAT" => &mut self.dataformat,
+            "SEARCH" => &mut self.search,
+            "SEARCHTIME" => &mut self.searchtime,
+            "FORMAT" => &mut self.format,
+            "COUNT" => &mut self.count,
+            "DS2408INV" => &mut self.ds2408inv,
+            "OWDID" => &mut self.owdid,
+            "POLLTIME" => &mut self.polltime,
+            "OWDIDFORMAT" => &mut self.owdidformat,
+            "AUTOECON" => &mut self.autoecon,
+            _ => return Ok(()),
+        };
+        *field = val.parse()?;
+        Ok(())
+    }
+}
+
+pub(crate) const SETTING_KEYS: &[&str] = &[
+    "DEBUG",
+    "KALSEND",
+    "KALSENDTIME",
+    "KALREC",
+    "KALRECTIME",
+    "KALALARM",
+    "DATASEND",
+    "DATATIME",
+    "DATAFORMAT",
+    "SEARCH",
+    "SEARCHTIME",
+    "FORMAT",
+    "COUNT",
+    "DS2408INV",
+    "OWDID",
+    "POLLTIME",
+    "OWDIDFORMAT",
+    "AUTOECON",
+];
+
+fn setting(i: &str) -> PResult<'_, (&str, &str)> {
+    preceded(
+        contno,
+        separated_pair(
+            verify(alphanumeric1, |k: &str| SETTING_KEYS.contains(&k)),
+            cc('|'),
+            terminated(not_line_ending, line_ending),
+        ),
+    )(i)
+}
+
+/// Settings block: starts with CSE and ends with DIO
+pub fn settings(i: &str) -> PResult<'_, OW> {
+    map_res(
+        tuple((
+            header("CSE"),
+            remainder,
+            many_till(setting, delimited(header("DIO"), digit1, line_ending)),
+        )),
+        |(contno, cse, (items, dio))| -> Result<_> {
+            let mut s = Settings {
+                cse: cse.into(),
+                dio: dio.parse()?,
+                ..Settings::default()
+            };
+            for (key, val) in items {
+                s.set(key, val)?;
+            }
+            Ok(OW {
+                contno,
+                msg: Msg::Settings(s),
+            })
+        },
+    )(i)
+}
+
 fn identifier(i: &str) -> PResult<'_, &str> {
     recognize(many1(alt((alphanumeric1, tag("_")))))(i)
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, EnumString, AsRefStr)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Display, EnumString, AsRefStr, Serialize)]
+#[serde(into = "String")]
 pub enum Status {
     #[strum(serialize = "0", to_string = "online")]
     Online,
@@ -274,13 +496,14 @@ pub enum Status {
     Err3,
     #[strum(serialize = "5", to_string = "offline")]
     Offline,
+    #[default]
     #[strum(serialize = "10", to_string = "unconfigured")]
     Unconfigured,
 }
 
-impl Default for Status {
-    fn default() -> Self {
-        Self::Unconfigured
+impl From<Status> for String {
+    fn from(status: Status) -> Self {
+        status.to_string()
     }
 }
 
@@ -289,23 +512,19 @@ pub type List3 = Vec<DeviceInfo>;
 pub fn lst3(i: &str) -> PResult<'_, OW> {
     let (i, contno) = terminated(header("LST3"), remainder)(i)?;
     let head = format!("LST|{}_", contno);
-    let (i, items) = many_m_n(
-        1,
-        30,
-        map_res(
-            tuple((
-                preceded(tag(head.as_ref()), alphanumeric1),
-                preceded(cc('|'), alphanumeric1),
-                preceded(tag("|S_"), identifier),
-                preceded(cc('|'), alphanumeric1),
-                opt(preceded(cc('|'), not_line_ending)),
-                line_ending,
-            )),
-            |(busid, serno, status, artno, name, _nl)| {
-                DeviceInfo::new(contno, busid, serno, status, artno, name)
-            },
-        ),
-    )(i)?;
+    let (i, items) = many1(map_res(
+        tuple((
+            preceded(tag(head.as_ref()), alphanumeric1),
+            preceded(cc('|'), alphanumeric1),
+            preceded(tag("|S_"), identifier),
+            preceded(cc('|'), alphanumeric1),
+            opt(preceded(cc('|'), not_line_ending)),
+            line_ending,
+        )),
+        |(busid, serno, status, artno, name, _nl)| {
+            DeviceInfo::new(contno, busid, serno, status, artno, name)
+        },
+    ))(i)?;
     Ok((
         i,
         OW {
@@ -315,43 +534,84 @@ pub fn lst3(i: &str) -> PResult<'_, OW> {
     ))
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct Devstatus {
     pub addr: String,
     pub val: i32,
+    /// Value has been sent as bitstring (e.g., "00001001") and contains a bit mask
+    pub bits: bool,
 }
 
 impl Devstatus {
     /// "OWD3_4" -> Some(4)
     /// "SYS" -> None
     pub fn subaddr(&self) -> Option<u8> {
-        self.addr.rsplit('_').next().and_then(|v| v.parse::<u8>().ok())
+        self.addr
+            .rsplit('_')
+            .next()
+            .and_then(|v| v.parse::<u8>().ok())
     }
 }
 
-pub fn devstatus(i: &str) -> PResult<'_, OW> {
+/// Digital I/O states are additionally reported as 8 bit strings on sub-addresses 2 and 4 (e.g.,
+/// "OWD2_4|00001001" mirrors "OWD2_3|9"). Only values of exactly 8 digits qualify, since other
+/// devices report plain numbers like "10" on the same sub-addresses.
+fn bitstring(i: &str) -> PResult<'_, i32> {
     map_res(
-        tuple((
-            contno,
-            recognize(many1(alt((alphanumeric1, tag("_"))))),
-            cc('|'),
-            terminated(pair(opt(cc('-')), digit1), line_ending),
-        )),
-        |(contno, busaddr, _, (sign, value))| -> Result<_> {
+        terminated(recognize(count(one_of("01"), 8)), line_ending),
+        |bits| i32::from_str_radix(bits, 2),
+    )(i)
+}
+
+fn decimal(i: &str) -> PResult<'_, i32> {
+    map_res(
+        terminated(pair(opt(cc('-')), digit1), line_ending),
+        |(sign, value): (Option<char>, &str)| -> Result<_> {
             let val: i32 = value.parse()?;
-            Ok(OW {
-                contno,
-                msg: Msg::Devstatus(Devstatus {
-                    addr: busaddr.into(),
-                    val: if sign.is_some() { -val } else { val },
-                }),
-            })
+            Ok(if sign.is_some() { -val } else { val })
         },
     )(i)
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, EnumString, AsRefStr, IntoStaticStr)]
+pub fn devstatus(i: &str) -> PResult<'_, OW> {
+    let (i, (contno, busaddr)) = pair(
+        contno,
+        terminated(recognize(many1(alt((alphanumeric1, tag("_"))))), cc('|')),
+    )(i)?;
+    let (i, (val, bits)) = if busaddr.ends_with("_2") || busaddr.ends_with("_4") {
+        alt((map(bitstring, |v| (v, true)), map(decimal, |v| (v, false))))(i)?
+    } else {
+        map(decimal, |v| (v, false))(i)?
+    };
+    Ok((
+        i,
+        OW {
+            contno,
+            msg: Msg::Devstatus(Devstatus {
+                addr: busaddr.into(),
+                val,
+                bits,
+            }),
+        },
+    ))
+}
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Display,
+    EnumString,
+    AsRefStr,
+    IntoStaticStr,
+    Serialize,
+)]
+#[serde(into = "String")]
 pub enum DIO {
+    #[default]
     #[strum(serialize = "0", to_string = "Independent+Level")]
     IndependentLevel,
     #[strum(serialize = "1", to_string = "Independent+Edge")]
@@ -362,15 +622,9 @@ pub enum DIO {
     LinkedEdge,
 }
 
-impl Default for DIO {
-    fn default() -> Self {
-        DIO::IndependentLevel
-    }
-}
-
-impl Into<String> for DIO {
-    fn into(self) -> String {
-        self.to_string()
+impl From<DIO> for String {
+    fn from(dio: DIO) -> Self {
+        dio.to_string()
     }
 }
 
@@ -386,7 +640,7 @@ pub fn dio(i: &str) -> PResult<'_, OW> {
     )(i)
 }
 
-#[derive(Debug, Clone, Default, PartialEq)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
 pub struct OWDStatus {
     pub owd: u8,
     pub status: Status,
@@ -411,16 +665,237 @@ pub fn owdstatus(i: &str) -> PResult<'_, OW> {
     )(i)
 }
 
+/// Device name as reported in response to GET,OWD,NAME,<n>
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct OWDName {
+    pub owd: u8,
+    /// Empty if no name has been assigned
+    pub name: String,
+}
+
+/// Shares its prefix with [`owdstatus`], but carries a name instead of a status code. Names which
+/// consist of digits only are therefore indistinguishable from status codes.
+pub fn owdname(i: &str) -> PResult<'_, OW> {
+    map_res(
+        tuple((
+            contno,
+            delimited(tag("OWD_"), digit1, cc('|')),
+            terminated(not_line_ending, line_ending),
+        )),
+        |(contno, n, name): (u8, &str, &str)| -> Result<_> {
+            Ok(OW {
+                contno,
+                msg: Msg::OWDName(OWDName {
+                    owd: n.parse()?,
+                    name: name.trim().into(),
+                }),
+            })
+        },
+    )(i)
+}
+
+/// Section of the controller's command set
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, EnumString)]
+pub enum Section {
+    /// Controller itself
+    SYS,
+    /// 1-Wire bus
+    OWB,
+    /// 1-Wire devices
+    OWD,
+    /// Controller push buttons
+    KEY,
+}
+
+/// Shutter movement
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Move {
+    Close = 1,
+    Open = 2,
+    Stop = 3,
+}
+
+/// Command to be sent to the controller. Displays in the controller's ASCII syntax, e.g.
+/// `SET,OWD,OUT,2,3,1`. Commands without a specific variant are represented as generic
+/// [`Command::Get`] or [`Command::Set`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// GET,<section>,<key>[,<arg>...]
+    Get(Section, String, Vec<String>),
+    /// SET,<section>,<key>[,<arg>...]
+    Set(Section, String, Vec<String>),
+    /// Digital output of a 1-Wire device: SET,OWD,OUT,<devno>,<0..7>,<0|1>
+    Out { devno: u8, ch: u8, on: bool },
+    /// Dimmer channel level: SET,OWD,DIM,<devno>,<1|2>,<0..31>
+    Dim { devno: u8, ch: u8, level: u8 },
+    /// Shutter movement: SET,OWD,SHT,<devno>,<1|2|3>
+    Shutter { devno: u8, op: Move },
+    /// Controller digital output: SET,SYS,OUT,<1..5>,<0|1>
+    SysOut { ch: u8, on: bool },
+    /// Controller analog output in 1/100 V: SET,SYS,OUTA,<0..1000>
+    SysAna(u16),
+    /// Device name: SET,OWD,NAME,<devno>,<name>
+    Name { devno: u8, name: String },
+}
+
+impl Command {
+    pub fn get(section: Section, key: &str) -> Self {
+        Self::Get(section, key.into(), Vec::new())
+    }
+
+    pub fn set<A: ToString>(section: Section, key: &str, args: &[A]) -> Self {
+        Self::Set(
+            section,
+            key.into(),
+            args.iter().map(ToString::to_string).collect(),
+        )
+    }
+
+    /// Section and key, e.g. (OWD, "OUT")
+    pub fn key(&self) -> (Section, &str) {
+        match self {
+            Self::Get(s, k, _) | Self::Set(s, k, _) => (*s, k),
+            Self::Out { .. } => (Section::OWD, "OUT"),
+            Self::Dim { .. } => (Section::OWD, "DIM"),
+            Self::Shutter { .. } => (Section::OWD, "SHT"),
+            Self::SysOut { .. } => (Section::SYS, "OUT"),
+            Self::SysAna(_) => (Section::SYS, "OUTA"),
+            Self::Name { .. } => (Section::OWD, "NAME"),
+        }
+    }
+
+    /// Checks argument ranges.
+    pub fn validate(&self) -> Result<()> {
+        let ok = match self {
+            Self::Get(_, key, args) | Self::Set(_, key, args) => {
+                !key.is_empty()
+                    && key.bytes().all(|b| b.is_ascii_alphanumeric())
+                    && args
+                        .iter()
+                        .all(|a| !a.contains(|c: char| c == ',' || c.is_control()))
+            }
+            Self::Out { devno, ch, .. } => *devno > 0 && *ch <= 7,
+            Self::Dim { devno, ch, level } => *devno > 0 && (1..=2).contains(ch) && *level <= 31,
+            Self::Shutter { devno, .. } => *devno > 0,
+            Self::SysOut { ch, .. } => (1..=5).contains(ch),
+            Self::SysAna(centi) => *centi <= 1000,
+            // names become part of MQTT topics
+            Self::Name { devno, name } => {
+                *devno > 0
+                    && !name.is_empty()
+                    && name
+                        .bytes()
+                        .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+            }
+        };
+        if ok {
+            Ok(())
+        } else {
+            Err(Error::Command(self.to_string()))
+        }
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Get(s, key, args) | Self::Set(s, key, args) => {
+                let op = if let Self::Get(..) = self {
+                    "GET"
+                } else {
+                    "SET"
+                };
+                write!(f, "{},{},{}", op, s, key)?;
+                for a in args {
+                    write!(f, ",{}", a)?;
+                }
+                Ok(())
+            }
+            Self::Out { devno, ch, on } => write!(f, "SET,OWD,OUT,{},{},{}", devno, ch, *on as u8),
+            Self::Dim { devno, ch, level } => write!(f, "SET,OWD,DIM,{},{},{}", devno, ch, level),
+            Self::Shutter { devno, op } => write!(f, "SET,OWD,SHT,{},{}", devno, *op as u8),
+            Self::SysOut { ch, on } => write!(f, "SET,SYS,OUT,{},{}", ch, *on as u8),
+            Self::SysAna(centi) => write!(f, "SET,SYS,OUTA,{}", centi),
+            Self::Name { devno, name } => write!(f, "SET,OWD,NAME,{},{}", devno, name),
+        }
+    }
+}
+
+fn flag(s: &str) -> Result<bool> {
+    match s {
+        "0" => Ok(false),
+        "1" => Ok(true),
+        _ => Err(Error::Command(s.into())),
+    }
+}
+
+impl FromStr for Command {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::Command(s.into());
+        let parts: Vec<&str> = s.trim_end().split(',').collect();
+        let (op, section, key, args) = match parts.as_slice() {
+            [op, section, key, args @ ..] => (*op, *section, *key, args),
+            _ => return Err(invalid()),
+        };
+        let section: Section = section.parse().map_err(|_| invalid())?;
+        let cmd = match (op, section, key, args) {
+            ("SET", Section::OWD, "OUT", [d, c, v]) => Self::Out {
+                devno: d.parse()?,
+                ch: c.parse()?,
+                on: flag(v)?,
+            },
+            ("SET", Section::OWD, "DIM", [d, c, l]) => Self::Dim {
+                devno: d.parse()?,
+                ch: c.parse()?,
+                level: l.parse()?,
+            },
+            ("SET", Section::OWD, "SHT", [d, op]) => Self::Shutter {
+                devno: d.parse()?,
+                op: match *op {
+                    "1" => Move::Close,
+                    "2" => Move::Open,
+                    "3" => Move::Stop,
+                    _ => return Err(invalid()),
+                },
+            },
+            ("SET", Section::OWD, "NAME", [d, n]) => Self::Name {
+                devno: d.parse()?,
+                name: n.to_string(),
+            },
+            ("SET", Section::SYS, "OUT", [c, v]) => Self::SysOut {
+                ch: c.parse()?,
+                on: flag(v)?,
+            },
+            ("SET", Section::SYS, "OUTA", [v]) => Self::SysAna(v.parse()?),
+            ("GET", _, _, _) => Self::Get(section, key.into(), to_strings(args)),
+            ("SET", _, _, _) => Self::Set(section, key.into(), to_strings(args)),
+            _ => return Err(invalid()),
+        };
+        cmd.validate()?;
+        Ok(cmd)
+    }
+}
+
+fn to_strings(args: &[&str]) -> Vec<String> {
+    args.iter().map(|a| String::from(*a)).collect()
+}
+
 pub fn parse(i: &str) -> PResult<'_, OW> {
     alt((
+        settings,
         kal,
         inf,
-        err,
+        // `err` must precede `errlist` which shares the same header
+        alt((err, errlist, errsum, errowd)),
         evt,
         rst,
         rdy,
         save,
         kalsendtime,
+        kalrec,
+        kalrectime,
         dataprint,
         datatime,
         date,
@@ -428,7 +903,7 @@ pub fn parse(i: &str) -> PResult<'_, OW> {
         lst3,
         csi,
         dio,
-        owdstatus,
+        alt((owdstatus, owdname)),
         devstatus,
     ))(i)
 }
@@ -439,6 +914,144 @@ mod test {
     use assert_matches::assert_matches;
     use pretty_assertions::assert_eq;
 
+    #[test]
+    fn parse_settings() {
+        let input = "\
+1_CSE|0:02:40\n\
+1_DEBUG|0\n\
+1_KALSEND|1\n\
+1_KALSENDTIME|60\n\
+1_KALREC|1\n\
+1_KALRECTIME|60\n\
+1_KALALARM|0|2|1\n\
+1_DATASEND|1\n\
+1_DATATIME|10\n\
+1_DATAFORMAT|0\n\
+1_SEARCH|2\n\
+1_SEARCHTIME|10\n\
+1_FORMAT|2\n\
+1_COUNT|3\n\
+1_DS2408INV|1\n\
+1_OWDID|0\n\
+1_POLLTIME|2\n\
+1_OWDIDFORMAT|1\n\
+1_AUTOECON|0\n\
+1_DIO|3\n\
+1_FW|12029\n";
+        let (rem, ow) = parse(input).unwrap();
+        assert_eq!(rem, "1_FW|12029\n");
+        assert_eq!(
+            ow.msg,
+            Msg::Settings(Settings {
+                cse: "0:02:40".into(),
+                debug: 0,
+                kalsend: 1,
+                kalsendtime: 60,
+                kalrec: 1,
+                kalrectime: 60,
+                kalalarm: vec![0, 2, 1],
+                datasend: 1,
+                datatime: 10,
+                dataformat: 0,
+                search: 2,
+                searchtime: 10,
+                format: 2,
+                count: 3,
+                ds2408inv: 1,
+                owdid: 0,
+                polltime: 2,
+                owdidformat: 1,
+                autoecon: 0,
+                dio: 3,
+            })
+        );
+        // needs the complete block
+        assert_matches!(
+            settings("1_CSE|0:02:40\n1_DEBUG|0\n"),
+            Err(nom::Err::Incomplete(_))
+        );
+        // standalone DIO is unaffected
+        assert_matches!(parse("1_DIO|3\n").unwrap().1.msg, Msg::DIO(_));
+    }
+
+    #[test]
+    fn command_round_trip() {
+        for cmd in &[
+            "GET,SYS,INFO",
+            "GET,OWB,LISTALL1",
+            "GET,OWD,NAME,1",
+            "GET,KEY,DATA",
+            "SET,SYS,KALSENDTIME,60",
+            "SET,SYS,KALALARM,0,2,1",
+            "SET,SYS,SAVE",
+            "SET,OWD,OUT,2,7,1",
+            "SET,OWD,DIM,3,2,31",
+            "SET,OWD,SHT,4,3",
+            "SET,SYS,OUT,5,0",
+            "SET,SYS,OUTA,1000",
+            "SET,OWD,NAME,2,K9",
+        ] {
+            assert_eq!(cmd.parse::<Command>().unwrap().to_string(), *cmd);
+        }
+        let c = Command::Dim {
+            devno: 3,
+            ch: 1,
+            level: 17,
+        };
+        assert_eq!(c.to_string().parse::<Command>().unwrap(), c);
+        assert_eq!(
+            Command::set(Section::SYS, "DATATIME", &[30]).to_string(),
+            "SET,SYS,DATATIME,30"
+        );
+    }
+
+    #[test]
+    fn command_validation() {
+        for cmd in &[
+            "SET,OWD,OUT,2,8,1",
+            "SET,OWD,OUT,0,1,1",
+            "SET,OWD,OUT,2,1,2",
+            "SET,OWD,DIM,3,3,0",
+            "SET,OWD,DIM,3,1,32",
+            "SET,OWD,SHT,4,4",
+            "SET,SYS,OUT,6,1",
+            "SET,SYS,OUTA,1001",
+            "SET,SYS,OUTA,-1",
+            "SET,OWD,NAME,2,K/9",
+            "SET,OWD,NAME,0,K9",
+            "GET,FOO,BAR",
+            "GET,SYS",
+            "PUT,SYS,INFO",
+        ] {
+            assert!(
+                cmd.parse::<Command>().is_err(),
+                "{} should be rejected",
+                cmd
+            );
+        }
+        assert!(Command::SysAna(2000).validate().is_err());
+        assert!(Command::get(Section::SYS, "IN\nFO").validate().is_err());
+    }
+
+    #[test]
+    fn parse_kalrec() {
+        assert_eq!(
+            parse("1_KALREC|1\n1_KALRECTIME|60\n").unwrap(),
+            (
+                "1_KALRECTIME|60\n",
+                OW {
+                    contno: 1,
+                    msg: Msg::Kalrec('1')
+                }
+            )
+        );
+        assert_eq!(
+            parse("1_KALRECTIME|60\n").unwrap().1.msg,
+            Msg::Kalrectime(60)
+        );
+        assert!(kalrectime("1_KALRECTIME|300\n").is_err());
+    }
+
     #[test]
     fn parse_keepalive() {
         assert_matches!(
@@ -516,6 +1129,22 @@ LST|1_OWD4|FFFFFFFFFFFFFFFF|S_10|none|             \n\
         );
     }
 
+    #[test]
+    fn parse_long_lst3() {
+        let mut input = String::from("1_LST3|00:02:54\n");
+        for n in 1..=64 {
+            input += &format!("LST|1_OWD{}|FFFFFFFFFFFFFFFF|S_10|none\n", n);
+        }
+        input += "1_EVT|0:02:55\n";
+        match lst3(&input).unwrap().1.msg {
+            Msg::List3(items) => {
+                assert_eq!(items.len(), 64);
+                assert_eq!(items[63].busid, "OWD64");
+            }
+            m => panic!("unexpected {:?}", m),
+        }
+    }
+
     #[test]
     fn parse_devstatus_numeric() {
         assert_eq!(
@@ -526,20 +1155,77 @@ LST|1_OWD4|FFFFFFFFFFFFFFFF|S_10|none|             \n\
                     contno: 1,
                     msg: Msg::Devstatus(Devstatus {
                         addr: "OWD12_3".into(),
-                        val: 2
+                        val: 2,
+                        bits: false
                     })
                 }
             )
         );
     }
 
+    #[test]
+    fn parse_devstatus_bitstring() {
+        assert_eq!(
+            parse("1_OWD2_4|00001001\n1_OWD2_3|1001\n").unwrap(),
+            (
+                "1_OWD2_3|1001\n",
+                OW {
+                    contno: 1,
+                    msg: Msg::Devstatus(Devstatus {
+                        addr: "OWD2_4".into(),
+                        val: 9,
+                        bits: true
+                    })
+                }
+            )
+        );
+        assert_eq!(
+            parse("1_SYS1_2|10000000\n").unwrap().1.msg,
+            Msg::Devstatus(Devstatus {
+                addr: "SYS1_2".into(),
+                val: 128,
+                bits: true
+            })
+        );
+        // dimmer level
+        assert_eq!(
+            parse("1_OWD5_4|17\n").unwrap().1.msg,
+            Msg::Devstatus(Devstatus {
+                addr: "OWD5_4".into(),
+                val: 17,
+                bits: false
+            })
+        );
+        // bitstrings are always 8 digits wide, shorter or longer 0/1 values are numbers
+        for val in &[10, 100, 100000000] {
+            assert_eq!(
+                parse(&format!("1_OWD5_4|{}\n", val)).unwrap().1.msg,
+                Msg::Devstatus(Devstatus {
+                    addr: "OWD5_4".into(),
+                    val: *val,
+                    bits: false
+                })
+            );
+        }
+        // only even sub-addresses carry bitstrings
+        assert_eq!(
+            parse("1_OWD7_1|10000000\n").unwrap().1.msg,
+            Msg::Devstatus(Devstatus {
+                addr: "OWD7_1".into(),
+                val: 10000000,
+                bits: false
+            })
+        );
+    }
+
     #[test]
     fn parse_devstatus_sys() {
         assert_eq!(
             devstatus("2_SYS3|500\n").unwrap().1.msg,
             Msg::Devstatus(Devstatus {
                 addr: "SYS3".into(),
-                val: 500
+                val: 500,
+                bits: false
             })
         );
     }
@@ -550,7 +1236,8 @@ LST|1_OWD4|FFFFFFFFFFFFFFFF|S_10|none|             \n\
             devstatus("3_OWD16_1|-847\n").unwrap().1.msg,
             Msg::Devstatus(Devstatus {
                 addr: "OWD16_1".into(),
-                val: -847
+                val: -847,
+                bits: false
             })
         );
     }
@@ -579,4 +1266,61 @@ LST|1_OWD4|FFFFFFFFFFFFFFFF|S_10|none|             \n\
             }
         )
     }
+
+    #[test]
+    fn parse_error_counters() {
+        assert_eq!(parse("1_ERRSUM|12\n").unwrap().1.msg, Msg::ErrSum(12));
+        assert_eq!(
+            parse("1_ERROWD3|7\n").unwrap().1.msg,
+            Msg::ErrOwd(ErrOwd { owd: 3, count: 7 })
+        );
+        assert_eq!(
+            parse("1_ERR|0:03:02\n1_ERROWD1|0\n1_ERROWD2|4\n1_EVT|0:03:04\n")
+                .unwrap()
+                .1
+                .msg,
+            Msg::ErrList(vec![
+                ErrOwd { owd: 1, count: 0 },
+                ErrOwd { owd: 2, count: 4 }
+            ])
+        );
+        // plain error codes are not mistaken for list headers
+        assert_eq!(parse("1_ERR|3\n").unwrap().1.msg, Msg::Err(3));
+    }
+
+    #[test]
+    fn serialize_messages() {
+        let json = |s: &str| serde_json::to_string(&parse(s).unwrap().1).unwrap();
+        assert_eq!(
+            json("1_OWD2_4|00000101\n"),
+            r#"{"contno":1,"msg":{"Devstatus":{"addr":"OWD2_4","val":5,"bits":true}}}"#
+        );
+        assert_eq!(
+            json("1_OWD_3|5\n"),
+            r#"{"contno":1,"msg":{"OWDStatus":{"owd":3,"status":"offline"}}}"#
+        );
+        assert_eq!(
+            json("1_DIO|3\n"),
+            r#"{"contno":1,"msg":{"DIO":"Linked+Edge"}}"#
+        );
+    }
+
+    #[test]
+    fn parse_owd_name() {
+        assert_eq!(
+            parse("1_OWD_1|TEMP\n").unwrap().1.msg,
+            Msg::OWDName(OWDName {
+                owd: 1,
+                name: "TEMP".into()
+            })
+        );
+        assert_eq!(
+            parse("1_OWD_2|\n").unwrap().1.msg,
+            Msg::OWDName(OWDName {
+                owd: 2,
+                name: "".into()
+            })
+        );
+        assert_matches!(parse("1_OWD_2|0\n").unwrap().1.msg, Msg::OWDStatus(_));
+    }
 }
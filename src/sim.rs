@@ -0,0 +1,883 @@
+//! Controller simulator for offline testing
+//!
+//! Emulates a Controller2 (11340) with a set of virtual 1-Wire devices. The simulator speaks the
+//! controller's line protocol: it answers commands, reports device states periodically and sends
+//! keepalives. Faults can be injected from a script:
+//!
+//! ```text
+//! # <seconds after connect> <fault>
+//! 10 input 2 5
+//! 20 status 3 1
+//! 30 errors 2 4
+//! 40 reject 3
+//! 60 reset
+//! 90 silence 200
+//! 300 disconnect
+//! ```
+use crate::parser::{Command, Move, Section, SETTING_KEYS};
+
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, VecDeque};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::time::{sleep, sleep_until, Instant};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Invalid fault '{0}'")]
+    Fault(String),
+    #[error("Script line {0}: {1}")]
+    Script(usize, String),
+    #[error(transparent)]
+    IO(#[from] std::io::Error),
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Number of device slots on the bus
+const SLOTS: u8 = 30;
+/// Time between RST and RDY
+const BOOT_TIME: Duration = Duration::from_secs(2);
+
+/// Virtual 1-Wire device
+#[derive(Debug, Clone, PartialEq)]
+pub enum SimDevice {
+    /// Temperature/humidity sensor (11150), values in 1/100 °C and 1/100 %
+    TempHum { temp: i32, hum: i32 },
+    /// 8 channel digital I/O (11220)
+    Switch8 { inputs: u8, outputs: u8 },
+    /// 2 channel dimmer (11221), levels 0..31
+    Dimmer { buttons: u8, levels: [u8; 2] },
+    /// Shutter actuator (11231), state bits: 01 closing, 10 opening, 11 stopped
+    Shutter { buttons: u8, state: u8 },
+}
+
+impl SimDevice {
+    pub fn temphum() -> Self {
+        Self::TempHum {
+            temp: 2150,
+            hum: 4500,
+        }
+    }
+
+    pub fn switch8() -> Self {
+        Self::Switch8 {
+            inputs: 0,
+            outputs: 0,
+        }
+    }
+
+    pub fn dimmer() -> Self {
+        Self::Dimmer {
+            buttons: 0,
+            levels: [0, 0],
+        }
+    }
+
+    pub fn shutter() -> Self {
+        Self::Shutter {
+            buttons: 0,
+            state: 0b11,
+        }
+    }
+
+    fn artno(&self) -> &'static str {
+        match self {
+            Self::TempHum { .. } => "11150",
+            Self::Switch8 { .. } => "11220",
+            Self::Dimmer { .. } => "11221",
+            Self::Shutter { .. } => "11231",
+        }
+    }
+
+    /// 1-Wire family code, used to make up serial numbers
+    fn family(&self) -> u8 {
+        match self {
+            Self::TempHum { .. } => 0x26,
+            _ => 0x29,
+        }
+    }
+
+    /// Values per sub-address. Digital states are additionally reported as bitstrings.
+    fn values(&self) -> Vec<(u8, String)> {
+        let digital = |a: u8, v: u8| vec![(a, v.to_string()), (a + 1, format!("{:08b}", v))];
+        match *self {
+            Self::TempHum { temp, hum } => vec![
+                (1, temp.to_string()),
+                (2, "500".into()),
+                (3, hum.to_string()),
+                (4, dewpoint(temp, hum).to_string()),
+            ],
+            Self::Switch8 { inputs, outputs } => {
+                let mut v = digital(1, inputs);
+                v.extend(digital(3, outputs));
+                v
+            }
+            Self::Dimmer { buttons, levels } => vec![
+                (1, buttons.to_string()),
+                (2, format!("{:08b}", buttons)),
+                (3, levels[0].to_string()),
+                (4, levels[1].to_string()),
+            ],
+            Self::Shutter { buttons, state } => {
+                let mut v = digital(1, buttons);
+                v.extend(digital(3, state));
+                v
+            }
+        }
+    }
+}
+
+/// Magnus formula, good enough for made-up values
+fn dewpoint(temp: i32, hum: i32) -> i32 {
+    let (t, rh) = (temp as f64 / 100.0, (hum as f64 / 100.0).max(1.0));
+    let g = (rh / 100.0).ln() + 17.62 * t / (243.12 + t);
+    (243.12 * g / (17.62 - g) * 100.0).round() as i32
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Slot {
+    serno: String,
+    name: String,
+    /// S_n in device lists: 0 = online, 1..3 = errors, 10 = offline
+    status: u8,
+    errors: u32,
+    dev: SimDevice,
+}
+
+/// Scripted misbehaviour
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fault {
+    /// Drop the connection
+    Disconnect,
+    /// Send nothing (not even keepalives) for a while
+    Silence(Duration),
+    /// Reboot: announce RST, forget all settings, then announce RDY
+    Reset,
+    /// Change a device's status code
+    Status(u8, u8),
+    /// Add 1-Wire errors to a device's counter
+    Errors(u8, u32),
+    /// Reject the next command with the given error code
+    Reject(u16),
+    /// Change the inputs (buttons) of a device
+    Input(u8, u8),
+    /// Change the temperature of a sensor (1/100 °C)
+    Temp(u8, i32),
+    /// Send an arbitrary line
+    Raw(String),
+}
+
+impl FromStr for Fault {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::Fault(s.into());
+        let words: Vec<&str> = s.split_whitespace().collect();
+        /// Parses the `i`th word into the field's type, so that out-of-range values fail
+        fn num<T: FromStr>(words: &[&str], i: usize, s: &str) -> Result<T> {
+            words
+                .get(i)
+                .and_then(|w| w.parse().ok())
+                .ok_or_else(|| Error::Fault(s.into()))
+        }
+        let w = &words;
+        Ok(match words.first().copied() {
+            Some("disconnect") => Self::Disconnect,
+            Some("silence") => Self::Silence(Duration::from_secs(num(w, 1, s)?)),
+            Some("reset") => Self::Reset,
+            Some("status") => Self::Status(num(w, 1, s)?, num(w, 2, s)?),
+            Some("errors") => Self::Errors(num(w, 1, s)?, num(w, 2, s)?),
+            Some("reject") => Self::Reject(num(w, 1, s)?),
+            Some("input") => Self::Input(num(w, 1, s)?, num(w, 2, s)?),
+            Some("temp") => Self::Temp(num(w, 1, s)?, num(w, 2, s)?),
+            Some("raw") => Self::Raw(s.trim_start()[3..].trim().into()),
+            _ => return Err(invalid()),
+        })
+    }
+}
+
+/// Faults with their time of occurrence (relative to connection start)
+pub type Script = Vec<(Duration, Fault)>;
+
+/// Parses a fault script: one `<seconds> <fault>` pair per line. Empty lines and lines starting
+/// with `#` are ignored.
+pub fn parse_script(s: &str) -> Result<Script> {
+    let mut script = Vec::new();
+    for (n, line) in s.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let err = |e: String| Error::Script(n + 1, e);
+        let (t, fault) = line
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| err(line.into()))?;
+        let t = t
+            .parse()
+            .ok()
+            .and_then(|t| Duration::try_from_secs_f64(t).ok())
+            .ok_or_else(|| err(format!("invalid time '{}'", t)))?;
+        script.push((t, fault.parse().map_err(|e: Error| err(e.to_string()))?));
+    }
+    script.sort_by_key(|(t, _)| *t);
+    Ok(script)
+}
+
+fn default_settings() -> BTreeMap<String, String> {
+    [
+        ("DEBUG", "0"),
+        ("KALSEND", "1"),
+        ("KALSENDTIME", "60"),
+        ("KALREC", "0"),
+        ("KALRECTIME", "60"),
+        ("KALALARM", "0|2|1"),
+        ("DATASEND", "1"),
+        ("DATATIME", "10"),
+        ("DATAFORMAT", "0"),
+        ("SEARCH", "2"),
+        ("SEARCHTIME", "10"),
+        ("FORMAT", "2"),
+        ("COUNT", "3"),
+        ("DS2408INV", "1"),
+        ("OWDID", "0"),
+        ("POLLTIME", "2"),
+        ("OWDIDFORMAT", "1"),
+        ("AUTOECON", "0"),
+        ("DIO", "3"),
+        ("DATAPRINT", "1"),
+        ("ECHO", "1"),
+    ]
+    .iter()
+    .map(|(k, v)| (k.to_string(), v.to_string()))
+    .collect()
+}
+
+/// Virtual controller state
+#[derive(Debug, Clone)]
+pub struct Simulator {
+    pub contno: u8,
+    serno: String,
+    slots: BTreeMap<u8, Slot>,
+    sys_in: u8,
+    sys_out: u8,
+    sys_ana: u16,
+    /// Values set via SET,SYS,<KEY>
+    settings: BTreeMap<String, String>,
+    /// Error codes for upcoming commands
+    reject: VecDeque<u16>,
+    /// All command lines received (survives resets)
+    received: Vec<String>,
+    boot: Instant,
+}
+
+impl Simulator {
+    pub fn new(contno: u8) -> Self {
+        Self {
+            contno,
+            serno: "113402019V2.0-243".into(),
+            slots: BTreeMap::new(),
+            sys_in: 0,
+            sys_out: 0,
+            sys_ana: 0,
+            settings: default_settings(),
+            reject: VecDeque::new(),
+            received: Vec::new(),
+            boot: Instant::now(),
+        }
+    }
+
+    /// Sample installation: sensor, switch, dimmer and shutter on OWD1..4
+    pub fn demo(contno: u8) -> Self {
+        Self::new(contno)
+            .with_device(1, "TEMP", SimDevice::temphum())
+            .with_device(2, "K1", SimDevice::switch8())
+            .with_device(3, "D1", SimDevice::dimmer())
+            .with_device(4, "R1", SimDevice::shutter())
+    }
+
+    /// Puts a device into slot `owd` (1..30). An empty name is allowed.
+    pub fn with_device(mut self, owd: u8, name: &str, dev: SimDevice) -> Self {
+        assert!((1..=SLOTS).contains(&owd), "OWD number out of range");
+        self.slots.insert(
+            owd,
+            Slot {
+                serno: format!("{:02X}{:014X}", dev.family(), owd),
+                name: name.into(),
+                status: 0,
+                errors: 0,
+                dev,
+            },
+        );
+        self
+    }
+
+    /// Current state of a device
+    pub fn device(&self, owd: u8) -> Option<&SimDevice> {
+        self.slots.get(&owd).map(|s| &s.dev)
+    }
+
+    /// Command lines received so far, oldest first
+    pub fn received(&self) -> &[String] {
+        &self.received
+    }
+
+    fn setting(&self, key: &str) -> u64 {
+        self.settings
+            .get(key)
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0)
+    }
+
+    /// Keepalive interval, if keepalives are enabled
+    pub fn kalsendtime(&self) -> Option<Duration> {
+        match (self.setting("KALSEND"), self.setting("KALSENDTIME")) {
+            (1, t) if t > 0 => Some(Duration::from_secs(t)),
+            _ => None,
+        }
+    }
+
+    /// Interval of device state reports, if enabled
+    pub fn datatime(&self) -> Option<Duration> {
+        match (self.setting("DATAPRINT"), self.setting("DATATIME")) {
+            (1, t) if t > 0 => Some(Duration::from_secs(t)),
+            _ => None,
+        }
+    }
+
+    fn uptime(&self) -> String {
+        let s = self.boot.elapsed().as_secs();
+        format!("{}:{:02}:{:02}", s / 3600, s / 60 % 60, s % 60)
+    }
+
+    fn line<V: std::fmt::Display>(&self, key: &str, val: V) -> String {
+        format!("{}_{}|{}", self.contno, key, val)
+    }
+
+    fn error(&self, code: u16) -> Vec<String> {
+        vec![self.line("INF", self.uptime()), self.line("ERR", code)]
+    }
+
+    fn evt(&self) -> String {
+        self.line("EVT", self.uptime())
+    }
+
+    /// Forgets everything set via commands, as after a power failure.
+    pub fn reset(&mut self) {
+        self.settings = default_settings();
+        self.sys_out = 0;
+        self.sys_ana = 0;
+        self.reject.clear();
+        for slot in self.slots.values_mut() {
+            match &mut slot.dev {
+                SimDevice::Switch8 { outputs, .. } => *outputs = 0,
+                SimDevice::Dimmer { levels, .. } => *levels = [0, 0],
+                SimDevice::Shutter { state, .. } => *state = 0b11,
+                SimDevice::TempHum { .. } => (),
+            }
+        }
+        self.boot = Instant::now();
+    }
+
+    /// Processes a single command line and returns the controller's answer.
+    pub fn command(&mut self, line: &str) -> Vec<String> {
+        self.received.push(line.trim().to_owned());
+        if let Some(code) = self.reject.pop_front() {
+            return self.error(code);
+        }
+        let cmd: Command = match line.parse() {
+            Ok(cmd) => cmd,
+            Err(_) => return self.error(1),
+        };
+        use Section::*;
+        match cmd {
+            Command::Get(section, key, args) => match (section, key.as_str(), args.as_slice()) {
+                (SYS, "INFO", []) => self.info(),
+                (SYS, "SETTING", []) => self.settings_block(),
+                (SYS, "DATA", []) => self.report(),
+                (SYS, "CONTNO", []) => vec![self.line("CONTNO", self.contno)],
+                (OWB, "LISTALL1", []) => self.list(),
+                (OWB, "ERRSUM", []) => {
+                    vec![self.line("ERRSUM", self.slots.values().map(|s| s.errors).sum::<u32>())]
+                }
+                (OWB, "ERROWD", [n]) => match n.parse::<u8>() {
+                    Ok(n) if (1..=SLOTS).contains(&n) => vec![self.line(
+                        &format!("ERROWD{}", n),
+                        self.slots.get(&n).map_or(0, |s| s.errors),
+                    )],
+                    _ => self.error(3),
+                },
+                (OWB, "ERRLISTALL1", []) => self.errlist(),
+                (OWD, "NAME", [n]) => match n.parse::<u8>() {
+                    Ok(n) if (1..=SLOTS).contains(&n) => vec![self.line(
+                        &format!("OWD_{}", n),
+                        self.slots.get(&n).map_or("", |s| &s.name),
+                    )],
+                    _ => self.error(3),
+                },
+                (_, key, []) => match self.settings.get(key) {
+                    Some(v) => vec![self.line(key, v)],
+                    None => self.error(4),
+                },
+                _ => self.error(4),
+            },
+            Command::Set(section, key, args) => match (section, key.as_str()) {
+                (SYS, "SAVE") => vec![self.line("SAVE", 1)],
+                (SYS, "KAL") => vec![self.line("KAL", 1)],
+                (SYS, "DATE") | (SYS, "TIME") => vec![self.line(&key, args.join(","))],
+                (_, key) if self.settings.contains_key(key) && !args.is_empty() => {
+                    let val = args.join("|");
+                    self.settings.insert(key.into(), val.clone());
+                    vec![self.line(key, val)]
+                }
+                _ => self.error(3),
+            },
+            Command::Out { devno, ch, on } => {
+                match self.slots.get_mut(&devno).map(|s| &mut s.dev) {
+                    Some(SimDevice::Switch8 { outputs, .. }) => {
+                        *outputs = *outputs & !(1 << ch) | (on as u8) << ch;
+                        self.changed(devno, &[3, 4])
+                    }
+                    _ => self.error(3),
+                }
+            }
+            Command::Dim { devno, ch, level } => {
+                match self.slots.get_mut(&devno).map(|s| &mut s.dev) {
+                    Some(SimDevice::Dimmer { levels, .. }) => {
+                        levels[ch as usize - 1] = level;
+                        self.changed(devno, &[ch + 2])
+                    }
+                    _ => self.error(3),
+                }
+            }
+            Command::Shutter { devno, op } => {
+                match self.slots.get_mut(&devno).map(|s| &mut s.dev) {
+                    Some(SimDevice::Shutter { state, .. }) => {
+                        *state = match op {
+                            Move::Close => 0b01,
+                            Move::Open => 0b10,
+                            Move::Stop => 0b11,
+                        };
+                        self.changed(devno, &[3, 4])
+                    }
+                    _ => self.error(3),
+                }
+            }
+            Command::SysOut { ch, on } => {
+                self.sys_out = self.sys_out & !(1 << (ch - 1)) | (on as u8) << (ch - 1);
+                self.sys_report(&["SYS2_1", "SYS2_2"])
+            }
+            Command::SysAna(centi) => {
+                self.sys_ana = centi;
+                self.sys_report(&["SYS3"])
+            }
+            Command::Name { devno, name } => match self.slots.get_mut(&devno) {
+                Some(slot) => {
+                    slot.name = name;
+                    vec![self.line(&format!("OWD_{}", devno), &self.slots[&devno].name)]
+                }
+                None => self.error(3),
+            },
+        }
+    }
+
+    /// Applies a fault which affects the controller's state and returns resulting messages.
+    /// Connection-level faults (disconnect, silence, reset) are handled by [`serve_conn`].
+    pub fn inject(&mut self, fault: &Fault) -> Vec<String> {
+        match fault {
+            Fault::Status(owd, status) => match self.slots.get_mut(owd) {
+                Some(slot) => {
+                    slot.status = *status;
+                    vec![self.line(&format!("OWD_{}", owd), status)]
+                }
+                None => Vec::new(),
+            },
+            Fault::Errors(owd, n) => {
+                if let Some(slot) = self.slots.get_mut(owd) {
+                    slot.errors += n;
+                }
+                Vec::new()
+            }
+            Fault::Reject(code) => {
+                self.reject.push_back(*code);
+                Vec::new()
+            }
+            Fault::Input(owd, val) => match self.slots.get_mut(owd).map(|s| &mut s.dev) {
+                Some(SimDevice::Switch8 { inputs, .. })
+                | Some(SimDevice::Dimmer {
+                    buttons: inputs, ..
+                })
+                | Some(SimDevice::Shutter {
+                    buttons: inputs, ..
+                }) => {
+                    *inputs = *val;
+                    self.changed(*owd, &[1, 2])
+                }
+                _ => Vec::new(),
+            },
+            Fault::Temp(owd, t) => match self.slots.get_mut(owd).map(|s| &mut s.dev) {
+                Some(SimDevice::TempHum { temp, .. }) => {
+                    *temp = *t;
+                    self.changed(*owd, &[1, 4])
+                }
+                _ => Vec::new(),
+            },
+            Fault::Raw(line) => vec![line.clone()],
+            Fault::Disconnect | Fault::Silence(_) | Fault::Reset => Vec::new(),
+        }
+    }
+
+    fn info(&self) -> Vec<String> {
+        vec![
+            self.line("CSI", self.uptime()),
+            self.line("DATE", "01.01.21"),
+            self.line("TIME", self.uptime()),
+            self.line("ARTNO", 11340),
+            self.line("SERNO", &self.serno),
+            self.line("FW", "V1.20_29b"),
+            self.line("HW", "V2.0"),
+            self.line("CONTNO", self.contno),
+        ]
+    }
+
+    fn settings_block(&self) -> Vec<String> {
+        let mut res = vec![self.line("CSE", self.uptime())];
+        for key in SETTING_KEYS {
+            res.push(self.line(key, &self.settings[*key]));
+        }
+        res.push(self.line("DIO", &self.settings["DIO"]));
+        res
+    }
+
+    fn list(&self) -> Vec<String> {
+        let mut res = vec![self.line("LST3", self.uptime())];
+        for n in 1..=SLOTS {
+            res.push(match self.slots.get(&n) {
+                Some(s) if s.status != 10 => format!(
+                    "LST|{}_OWD{}|{}|S_{}|{}|{}",
+                    self.contno,
+                    n,
+                    s.serno,
+                    s.status,
+                    s.dev.artno(),
+                    s.name
+                ),
+                _ => format!("LST|{}_OWD{}|FFFFFFFFFFFFFFFF|S_10|none|", self.contno, n),
+            });
+        }
+        // the list is only complete once something else follows
+        res.push(self.evt());
+        res
+    }
+
+    fn errlist(&self) -> Vec<String> {
+        let mut res = vec![self.line("ERR", self.uptime())];
+        for n in 1..=SLOTS {
+            res.push(self.line(
+                &format!("ERROWD{}", n),
+                self.slots.get(&n).map_or(0, |s| s.errors),
+            ));
+        }
+        res.push(self.evt());
+        res
+    }
+
+    fn sys_report(&self, addrs: &[&str]) -> Vec<String> {
+        addrs
+            .iter()
+            .map(|a| match *a {
+                "SYS1_1" => self.line(a, self.sys_in),
+                "SYS1_2" => self.line(a, format!("{:08b}", self.sys_in)),
+                "SYS2_1" => self.line(a, self.sys_out),
+                "SYS2_2" => self.line(a, format!("{:08b}", self.sys_out)),
+                _ => self.line(a, self.sys_ana),
+            })
+            .collect()
+    }
+
+    /// State report of selected sub-addresses of a device
+    fn changed(&self, owd: u8, subaddrs: &[u8]) -> Vec<String> {
+        match self.slots.get(&owd) {
+            Some(s) => s
+                .dev
+                .values()
+                .into_iter()
+                .filter(|(a, _)| subaddrs.contains(a))
+                .map(|(a, v)| self.line(&format!("OWD{}_{}", owd, a), v))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Periodic report of all states (DATAPRINT)
+    pub fn report(&self) -> Vec<String> {
+        let mut res = vec![self.evt()];
+        res.extend(self.sys_report(&["SYS1_1", "SYS1_2", "SYS2_1", "SYS2_2", "SYS3"]));
+        for (n, s) in self.slots.iter().filter(|(_, s)| s.status == 0) {
+            for (a, v) in s.dev.values() {
+                res.push(self.line(&format!("OWD{}_{}", n, a), v));
+            }
+        }
+        res
+    }
+
+    pub fn keepalive(&self) -> String {
+        self.line("KAL", 1)
+    }
+}
+
+pub type Shared = Arc<Mutex<Simulator>>;
+
+async fn send<W: AsyncWrite + Unpin>(writer: &mut W, lines: &[String]) -> std::io::Result<()> {
+    for line in lines {
+        debug!(">>> {}", line);
+        writer.write_all(line.as_bytes()).await?;
+        writer.write_all(b"\n").await?;
+    }
+    writer.flush().await
+}
+
+/// Talks to a single client until it disconnects or the script says so.
+pub async fn serve_conn<R, W>(
+    sim: Shared,
+    reader: R,
+    mut writer: W,
+    script: &[(Duration, Fault)],
+) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut lines = BufReader::new(reader).lines();
+    let start = Instant::now();
+    let mut script = script.iter();
+    let mut next_fault = script.next();
+    let mut last_kal = start;
+    let mut last_data = start;
+    let mut quiet_until = start;
+    loop {
+        let (kal, data) = {
+            let sim = sim.lock();
+            (sim.kalsendtime(), sim.datatime())
+        };
+        let fault_due = next_fault.map(|(t, _)| start + *t);
+        let out = tokio::select! {
+            line = lines.next_line() => match line? {
+                Some(line) if line.trim().is_empty() => continue,
+                Some(line) => {
+                    debug!("<<< {}", line.trim());
+                    sim.lock().command(line.trim())
+                }
+                None => return Ok(()),
+            },
+            _ = sleep_until(last_kal + kal.unwrap_or_default()), if kal.is_some() => {
+                last_kal = Instant::now();
+                vec![sim.lock().keepalive()]
+            },
+            _ = sleep_until(last_data + data.unwrap_or_default()), if data.is_some() => {
+                last_data = Instant::now();
+                sim.lock().report()
+            },
+            _ = sleep_until(fault_due.unwrap_or_else(Instant::now)), if fault_due.is_some() => {
+                let fault = &next_fault.expect("fault due").1;
+                next_fault = script.next();
+                info!("Injecting fault: {:?}", fault);
+                match fault {
+                    Fault::Disconnect => return Ok(()),
+                    Fault::Silence(d) => {
+                        quiet_until = Instant::now() + *d;
+                        Vec::new()
+                    }
+                    Fault::Reset => {
+                        let rst = sim.lock().line("RST", 1);
+                        send(&mut writer, &[rst]).await?;
+                        sim.lock().reset();
+                        sleep(BOOT_TIME).await;
+                        vec![sim.lock().line("RDY", 1)]
+                    }
+                    f => sim.lock().inject(f),
+                }
+            },
+        };
+        if Instant::now() >= quiet_until {
+            send(&mut writer, &out).await?;
+        }
+    }
+}
+
+/// Accepts connections and serves each of them in the background. The fault script restarts
+/// with each connection.
+pub async fn serve(listener: TcpListener, sim: Shared, script: Script) -> Result<()> {
+    let script = Arc::new(script);
+    loop {
+        let (conn, peer) = listener.accept().await?;
+        info!("Connection from {}", peer);
+        let (sim, script) = (sim.clone(), script.clone());
+        tokio::spawn(async move {
+            let (reader, writer) = conn.into_split();
+            match serve_conn(sim, reader, writer, &script).await {
+                Ok(()) => info!("Connection to {} closed", peer),
+                Err(e) => warn!("Connection to {}: {}", peer, e),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::{parse, Msg};
+    use crate::ControllerConnection;
+    use assert_matches::assert_matches;
+
+    /// Parses simulator output with the bridge's parser.
+    fn msgs(lines: Vec<String>) -> Vec<Msg> {
+        let mut input = lines.join("\n") + "\n";
+        let mut res = Vec::new();
+        while !input.is_empty() {
+            let (rem, ow) = parse(&input).expect("parseable output");
+            res.push(ow.msg);
+            input = rem.to_owned();
+        }
+        res
+    }
+
+    #[test]
+    fn answers_parse() {
+        let mut sim = Simulator::demo(1);
+        assert_matches!(msgs(sim.command("GET,SYS,INFO"))[..], [Msg::CSI(_)]);
+        assert_matches!(
+            &msgs(sim.command("GET,SYS,SETTING"))[..],
+            [Msg::Settings(s)] if s.kalsendtime == 60
+        );
+        match &msgs(sim.command("GET,OWB,LISTALL1"))[..] {
+            [Msg::List3(l), Msg::Evt(_)] => {
+                assert_eq!(l.len(), 30);
+                assert_eq!(l[1].name.as_deref(), Some("K1"));
+                assert_eq!(l[1].artno, "11220");
+            }
+            other => panic!("unexpected list {:?}", other),
+        }
+        assert_matches!(
+            msgs(sim.command("GET,OWB,ERRLISTALL1"))[..],
+            [Msg::ErrList(_), Msg::Evt(_)]
+        );
+        assert!(msgs(sim.report()).len() > 20);
+    }
+
+    #[test]
+    fn commands_change_state() {
+        let mut sim = Simulator::demo(1);
+        assert_eq!(
+            sim.command("SET,OWD,OUT,2,2,1"),
+            ["1_OWD2_3|4", "1_OWD2_4|00000100"]
+        );
+        assert_eq!(
+            sim.device(2),
+            Some(&SimDevice::Switch8 {
+                inputs: 0,
+                outputs: 4
+            })
+        );
+        assert_eq!(sim.command("SET,OWD,DIM,3,2,17"), ["1_OWD3_4|17"]);
+        assert_eq!(
+            sim.command("SET,OWD,SHT,4,1"),
+            ["1_OWD4_3|1", "1_OWD4_4|00000001"]
+        );
+        assert_eq!(sim.command("SET,SYS,KALSENDTIME,30"), ["1_KALSENDTIME|30"]);
+        assert_eq!(sim.kalsendtime(), Some(Duration::from_secs(30)));
+        assert_eq!(sim.command("SET,OWD,NAME,2,K9"), ["1_OWD_2|K9"]);
+        // not a switch
+        assert_eq!(sim.command("SET,OWD,OUT,1,2,1")[1], "1_ERR|3");
+        sim.reset();
+        assert_eq!(sim.kalsendtime(), Some(Duration::from_secs(60)));
+        assert!(sim
+            .command("GET,SYS,DATA")
+            .contains(&"1_OWD2_3|0".to_string()));
+        assert_eq!(sim.received()[0], "SET,OWD,OUT,2,2,1");
+        assert_eq!(sim.received().len(), 7);
+    }
+
+    #[test]
+    fn faults() {
+        let mut sim = Simulator::demo(1);
+        sim.inject(&Fault::Reject(5));
+        assert_eq!(sim.command("SET,SYS,KAL,1")[1], "1_ERR|5");
+        assert_eq!(sim.command("SET,SYS,KAL,1"), ["1_KAL|1"]);
+        sim.inject(&Fault::Errors(2, 4));
+        assert_eq!(sim.command("GET,OWB,ERRSUM"), ["1_ERRSUM|4"]);
+        assert_eq!(sim.inject(&Fault::Status(3, 1)), ["1_OWD_3|1"]);
+        assert_eq!(
+            sim.inject(&Fault::Input(2, 5)),
+            ["1_OWD2_1|5", "1_OWD2_2|00000101"]
+        );
+    }
+
+    #[test]
+    fn parse_fault_script() {
+        assert_eq!(
+            parse_script("# comment\n30 reset\n\n10 input 2 5\n12.5 raw 1_OWD_2|3\n").unwrap(),
+            vec![
+                (Duration::from_secs(10), Fault::Input(2, 5)),
+                (
+                    Duration::from_secs_f64(12.5),
+                    Fault::Raw("1_OWD_2|3".into())
+                ),
+                (Duration::from_secs(30), Fault::Reset),
+            ]
+        );
+        assert_matches!(parse_script("10 explode"), Err(Error::Script(1, _)));
+        assert_matches!(parse_script("ten reset"), Err(Error::Script(1, _)));
+        assert_matches!(parse_script("-5 reset"), Err(Error::Script(1, _)));
+        assert_eq!(
+            "temp 4 -500".parse::<Fault>().unwrap(),
+            Fault::Temp(4, -500)
+        );
+        assert_matches!("status 300 1".parse::<Fault>(), Err(Error::Fault(_)));
+        assert_matches!("reject 70000".parse::<Fault>(), Err(Error::Fault(_)));
+        assert_matches!(parse_script("\n nan reset"), Err(Error::Script(2, _)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn talk_to_controller_connection() {
+        let (bridge, sim_end) = tokio::io::duplex(1 << 12);
+        let sim = Arc::new(Mutex::new(Simulator::demo(1)));
+        let script = vec![(Duration::from_secs(5), Fault::Input(2, 1))];
+        let (r, w) = tokio::io::split(sim_end);
+        tokio::spawn(async move { serve_conn(sim, r, w, &script).await });
+        let (r, w) = tokio::io::split(bridge);
+        let mut c = ControllerConnection::from_streams(r, w);
+        assert_eq!(c.csi().await.unwrap().contno, 1);
+        assert_matches!(c.list().await.unwrap().msg, Msg::List3(_));
+        assert_matches!(
+            c.command(Command::Out {
+                devno: 2,
+                ch: 0,
+                on: true
+            })
+            .await,
+            Ok(None)
+        );
+        // scripted button press
+        loop {
+            match c.get().await {
+                Some(Ok(ow))
+                    if ow.msg
+                        == Msg::Devstatus(crate::parser::Devstatus {
+                            addr: "OWD2_1".into(),
+                            val: 1,
+                            bits: false,
+                        }) =>
+                {
+                    break
+                }
+                Some(_) => (),
+                None => panic!("connection closed"),
+            }
+        }
+    }
+}
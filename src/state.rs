@@ -0,0 +1,131 @@
+//! Persistent output states
+//!
+//! Remembers commanded outputs of each device (keyed by serial number) so that they can be
+//! reapplied after a controller reset or power failure. The store is kept as JSON file:
+//!
+//! ```json
+//! {
+//!   "4300001982956429": {"ch1": 1, "ch2": 0},
+//!   "EF000019096A4026": {"position": 100}
+//! }
+//! ```
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Cannot read state file {0}: {1}")]
+    Read(String, #[source] io::Error),
+    #[error("Cannot write state file {0}: {1}")]
+    Write(String, #[source] io::Error),
+    #[error("Cannot parse state file {0}: {1}")]
+    Parse(String, #[source] serde_json::Error),
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Output values by channel name (e.g., "ch1" or "position")
+pub type Outputs = BTreeMap<String, i32>;
+
+/// What to do with a device's outputs after a controller reset
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Restore {
+    /// Switch all outputs off
+    Off,
+    /// Reapply last known outputs
+    Last,
+    /// Don't touch outputs
+    #[default]
+    Leave,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StateStore {
+    path: Option<PathBuf>,
+    devices: BTreeMap<String, Outputs>,
+}
+
+impl StateStore {
+    /// Loads state file. A missing file results in an empty store.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let name = path.display().to_string();
+        let devices = match fs::read(path) {
+            Ok(content) => serde_json::from_slice(&content).map_err(|e| Error::Parse(name, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(Error::Read(name, e)),
+        };
+        Ok(Self {
+            path: Some(path.to_owned()),
+            devices,
+        })
+    }
+
+    /// Whether the store is backed by a file.
+    pub fn persistent(&self) -> bool {
+        self.path.is_some()
+    }
+
+    pub fn get(&self, serno: &str) -> Option<&Outputs> {
+        self.devices.get(serno)
+    }
+
+    /// Records outputs of a device. The state file is rewritten if anything has changed.
+    pub fn update(&mut self, serno: &str, outputs: Outputs) -> Result<()> {
+        if outputs.is_empty() || self.devices.get(serno) == Some(&outputs) {
+            return Ok(());
+        }
+        self.devices.insert(serno.to_owned(), outputs);
+        self.save()
+    }
+
+    fn save(&self) -> Result<()> {
+        let path = match &self.path {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        let name = path.display().to_string();
+        // write atomically so that a crash does not leave a truncated file behind
+        let tmp = path.with_extension("tmp");
+        fs::write(
+            &tmp,
+            serde_json::to_vec_pretty(&self.devices).expect("serializable"),
+        )
+        .and_then(|_| fs::rename(&tmp, path))
+        .map_err(|e| Error::Write(name, e))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn persist_and_reload() {
+        let dir = std::env::temp_dir().join(format!("esera-state-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("1.json");
+        let mut store = StateStore::open(&path).unwrap();
+        assert_eq!(store.get("4300001982956429"), None);
+        let outputs: Outputs = vec![("ch1".into(), 1), ("ch2".into(), 0)]
+            .into_iter()
+            .collect();
+        store.update("4300001982956429", outputs.clone()).unwrap();
+        let store = StateStore::open(&path).unwrap();
+        assert_eq!(store.get("4300001982956429"), Some(&outputs));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parse_policy() {
+        assert_eq!(
+            serde_json::from_str::<Restore>("\"last\"").unwrap(),
+            Restore::Last
+        );
+    }
+}
@@ -0,0 +1,242 @@
+//! Minimal in-process MQTT broker for end-to-end tests. Speaks just enough MQTT 3.1.1 for the
+//! bridge: QoS is downgraded to 0 on delivery, retained messages and last wills are supported.
+use bytes::BytesMut;
+use parking_lot::Mutex;
+use rumqttc::{
+    matches, ConnAck, ConnectReturnCode, Packet, PubAck, Publish, QoS, SubAck, SubscribeReasonCode,
+    UnsubAck,
+};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio::time::{sleep, Instant};
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+/// How long to wait for expected messages
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Msg {
+    pub topic: String,
+    pub payload: String,
+    pub retain: bool,
+}
+
+impl Msg {
+    fn new(topic: &str, payload: &[u8], retain: bool) -> Self {
+        Self {
+            topic: topic.into(),
+            payload: String::from_utf8_lossy(payload).into_owned(),
+            retain,
+        }
+    }
+}
+
+struct Client {
+    subs: Arc<Mutex<Vec<String>>>,
+    tx: mpsc::UnboundedSender<Publish>,
+}
+
+#[derive(Default)]
+struct State {
+    retained: BTreeMap<String, String>,
+    log: Vec<Msg>,
+    clients: Vec<Client>,
+}
+
+impl State {
+    fn route(&mut self, msg: Msg) {
+        if msg.retain {
+            if msg.payload.is_empty() {
+                self.retained.remove(&msg.topic);
+            } else {
+                self.retained.insert(msg.topic.clone(), msg.payload.clone());
+            }
+        }
+        self.clients.retain(|c| !c.tx.is_closed());
+        for c in &self.clients {
+            if c.subs.lock().iter().any(|f| matches(&msg.topic, f)) {
+                let publ = Publish::new(&msg.topic, QoS::AtMostOnce, msg.payload.as_bytes());
+                c.tx.send(publ).ok();
+            }
+        }
+        self.log.push(msg);
+    }
+}
+
+#[derive(Clone)]
+pub struct Broker {
+    pub addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+}
+
+impl Broker {
+    /// Listens on a random local port.
+    pub async fn start() -> Self {
+        Self::listen(None).await
+    }
+
+    /// Like [`start`](Self::start), but accepts only TLS connections.
+    pub async fn start_tls(tls: ServerConfig) -> Self {
+        Self::listen(Some(TlsAcceptor::from(Arc::new(tls)))).await
+    }
+
+    async fn listen(tls: Option<TlsAcceptor>) -> Self {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let broker = Self {
+            addr: listener.local_addr().unwrap(),
+            state: Arc::default(),
+        };
+        let state = broker.state.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let state = state.clone();
+                match &tls {
+                    Some(acceptor) => {
+                        let acceptor = acceptor.clone();
+                        tokio::spawn(async move {
+                            // failed handshakes just don't get a session
+                            if let Ok(stream) = acceptor.accept(stream).await {
+                                session(stream, state).await
+                            }
+                        });
+                    }
+                    None => {
+                        tokio::spawn(session(stream, state));
+                    }
+                }
+            }
+        });
+        broker
+    }
+
+    /// Publishes a message as any other MQTT client (e.g., Home Assistant) would.
+    pub fn publish(&self, topic: &str, payload: &str, retain: bool) {
+        self.state
+            .lock()
+            .route(Msg::new(topic, payload.as_bytes(), retain))
+    }
+
+    /// All messages published so far, oldest first
+    pub fn published(&self) -> Vec<Msg> {
+        self.state.lock().log.clone()
+    }
+
+    pub fn retained(&self, topic: &str) -> Option<String> {
+        self.state.lock().retained.get(topic).cloned()
+    }
+
+    /// Waits until `payload` has been published to `topic`. Panics on timeout.
+    pub async fn wait_for(&self, topic: &str, payload: &str) {
+        let deadline = Instant::now() + TIMEOUT;
+        while Instant::now() < deadline {
+            if self
+                .published()
+                .iter()
+                .any(|m| m.topic == topic && m.payload == payload)
+            {
+                return;
+            }
+            sleep(Duration::from_millis(20)).await;
+        }
+        let seen: Vec<_> = self
+            .published()
+            .into_iter()
+            .filter(|m| m.topic == topic)
+            .map(|m| m.payload)
+            .collect();
+        panic!("Expected '{} {}', seen {:?}", topic, payload, seen)
+    }
+}
+
+async fn session<S>(stream: S, state: Arc<Mutex<State>>)
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let (mut rd, mut wr) = tokio::io::split(stream);
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let subs = Arc::new(Mutex::new(Vec::new()));
+    state.lock().clients.push(Client {
+        subs: subs.clone(),
+        tx: tx.clone(),
+    });
+    let mut buf = BytesMut::with_capacity(1 << 12);
+    let mut out = BytesMut::new();
+    let mut will = None;
+    'conn: loop {
+        loop {
+            let packet = match rumqttc::read(&mut buf, 1 << 20) {
+                Ok(packet) => packet,
+                Err(rumqttc::Error::InsufficientBytes(_)) => break,
+                Err(_) => break 'conn,
+            };
+            match packet {
+                Packet::Connect(c) => {
+                    will = c
+                        .last_will
+                        .map(|w| Msg::new(&w.topic, &w.message, w.retain));
+                    ConnAck::new(ConnectReturnCode::Success, false)
+                        .write(&mut out)
+                        .unwrap();
+                }
+                Packet::Publish(p) => {
+                    if p.qos != QoS::AtMostOnce {
+                        PubAck::new(p.pkid).write(&mut out).unwrap();
+                    }
+                    state.lock().route(Msg::new(&p.topic, &p.payload, p.retain));
+                }
+                Packet::Subscribe(s) => {
+                    let state = state.lock();
+                    for f in &s.filters {
+                        subs.lock().push(f.path.clone());
+                        for (topic, payload) in &state.retained {
+                            if matches(topic, &f.path) {
+                                let mut publ =
+                                    Publish::new(topic, QoS::AtMostOnce, payload.as_bytes());
+                                publ.retain = true;
+                                tx.send(publ).ok();
+                            }
+                        }
+                    }
+                    let codes = s
+                        .filters
+                        .iter()
+                        .map(|_| SubscribeReasonCode::Success(QoS::AtMostOnce))
+                        .collect();
+                    SubAck::new(s.pkid, codes).write(&mut out).unwrap();
+                }
+                Packet::Unsubscribe(u) => {
+                    subs.lock().retain(|f| !u.topics.contains(f));
+                    UnsubAck::new(u.pkid).write(&mut out).unwrap();
+                }
+                Packet::PingReq => rumqttc::PingResp.write(&mut out).map(drop).unwrap(),
+                Packet::Disconnect => {
+                    will = None;
+                    break 'conn;
+                }
+                _ => (),
+            }
+        }
+        if !out.is_empty() {
+            let data = out.split();
+            if wr.write_all(&data).await.is_err() || wr.flush().await.is_err() {
+                break;
+            }
+        }
+        tokio::select! {
+            res = rd.read_buf(&mut buf) => match res {
+                Ok(0) | Err(_) => break,
+                Ok(_) => (),
+            },
+            Some(publ) = rx.recv() => publ.write(&mut out).map(drop).unwrap(),
+        }
+    }
+    if let Some(will) = will {
+        state.lock().route(will);
+    }
+}
@@ -1,5 +1,19 @@
+// Not every test crate uses all helpers
+#![allow(dead_code)]
+
+pub mod broker;
+
+use esera_mqtt::sim::{self, Simulator};
+use parking_lot::Mutex;
 use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Stdio};
+use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
+use tokio::time::{sleep, Instant};
+
+use broker::Broker;
 
 pub fn rexp_session<
     F: FnOnce(rexpect::session::StreamSession<TcpStream>) -> rexpect::errors::Result<()>
@@ -19,3 +33,105 @@ pub fn rexp_session<
     });
     addr
 }
+
+/// Complete setup for end-to-end tests: the `esera-bridge` binary talks to an in-process MQTT
+/// broker and a simulated controller. The bridge process is killed on drop.
+pub struct Bridge {
+    pub broker: Broker,
+    pub sim: sim::Shared,
+    child: Child,
+    conf_file: PathBuf,
+}
+
+impl Bridge {
+    pub async fn start(sim: Simulator) -> Self {
+        Self::with_config(sim, "").await
+    }
+
+    /// Starts the bridge with additional config file sections (except `[mqtt]`).
+    pub async fn with_config(sim: Simulator, conf: &str) -> Self {
+        let broker = Broker::start().await;
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0))
+            .await
+            .unwrap();
+        let ctrl_addr = listener.local_addr().unwrap();
+        let sim = Arc::new(Mutex::new(sim));
+        tokio::spawn(sim::serve(listener, sim.clone(), Vec::new()));
+        let conf_file = std::env::temp_dir().join(format!(
+            "esera-e2e-{}-{}.toml",
+            std::process::id(),
+            broker.addr.port()
+        ));
+        std::fs::write(
+            &conf_file,
+            format!(
+                "controllers = [\"{}\"]\n\n[mqtt]\nhost = \"127.0.0.1\"\nport = {}\n\n{}",
+                ctrl_addr,
+                broker.addr.port(),
+                conf
+            ),
+        )
+        .unwrap();
+        let verbose = std::env::var_os("RUST_LOG").is_some();
+        let child = std::process::Command::new(env!("CARGO_BIN_EXE_esera-bridge"))
+            .arg("-c")
+            .arg(&conf_file)
+            .env_remove("ESERA_PREFIX")
+            .env_remove("MQTT_HOST")
+            .env_remove("MQTT_PORT")
+            .env_remove("MQTT_CRED")
+            .stdout(Stdio::null())
+            .stderr(if verbose {
+                Stdio::inherit()
+            } else {
+                Stdio::null()
+            })
+            .spawn()
+            .expect("failed to start esera-bridge");
+        Self {
+            broker,
+            sim,
+            child,
+            conf_file,
+        }
+    }
+
+    /// Waits until the bridge has stopped sending commands to the controller, e.g. after
+    /// initializing all devices. The quiet period exceeds the bridge's response timeout so that
+    /// no earlier command is pending anymore.
+    pub async fn settle(&self) {
+        let mut seen = self.sim.lock().received().len();
+        loop {
+            sleep(Duration::from_millis(2500)).await;
+            let n = self.sim.lock().received().len();
+            if n == seen {
+                return;
+            }
+            seen = n;
+        }
+    }
+
+    /// Waits until the controller has received `cmd`. Panics on timeout.
+    pub async fn wait_for_command(&self, cmd: &str) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while Instant::now() < deadline {
+            if self.sim.lock().received().iter().any(|c| c == cmd) {
+                return;
+            }
+            sleep(Duration::from_millis(20)).await;
+        }
+        panic!(
+            "Controller did not receive {}, got {:?}",
+            cmd,
+            self.sim.lock().received()
+        )
+    }
+}
+
+impl Drop for Bridge {
+    fn drop(&mut self) {
+        self.child.kill().ok();
+        self.child.wait().ok();
+        std::fs::remove_file(&self.conf_file).ok();
+    }
+}
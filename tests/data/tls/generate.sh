@@ -0,0 +1,34 @@
+#!/bin/sh
+# Regenerates the TLS test fixtures: a CA, a server certificate for localhost and client
+# certificates with keys in the formats accepted by the bridge (PKCS#1 RSA, PKCS#8 EC and
+# PKCS#8 RSA).
+set -e
+cd "$(dirname "$0")"
+DAYS=36500
+
+openssl req -x509 -newkey rsa:2048 -nodes -keyout ca.key -out ca.pem -days $DAYS \
+    -subj "/CN=esera-mqtt test CA" \
+    -addext "basicConstraints=critical,CA:TRUE" -addext "keyUsage=critical,keyCertSign"
+
+sign() {
+    openssl req -new -key "$1.key" -subj "/CN=$2" -out "$1.csr"
+    printf "%s\n" "$3" > "$1.ext"
+    openssl x509 -req -in "$1.csr" -CA ca.pem -CAkey ca.key -CAcreateserial \
+        -days $DAYS -extfile "$1.ext" -out "$1.pem"
+    rm "$1.csr" "$1.ext"
+}
+
+openssl genrsa -traditional -out server.key 2048
+sign server localhost "subjectAltName=DNS:localhost
+extendedKeyUsage=serverAuth"
+
+openssl genrsa -traditional -out client.key 2048
+sign client esera-bridge "extendedKeyUsage=clientAuth"
+openssl pkcs8 -topk8 -nocrypt -in client.key -out client_rsa8.key
+
+openssl ecparam -name prime256v1 -genkey -noout | openssl pkcs8 -topk8 -nocrypt -out client_ec.key
+openssl req -new -key client_ec.key -subj "/CN=esera-bridge" -out client_ec.csr
+printf "extendedKeyUsage=clientAuth\n" > client_ec.ext
+openssl x509 -req -in client_ec.csr -CA ca.pem -CAkey ca.key -CAcreateserial -days $DAYS \
+    -extfile client_ec.ext -out client_ec.pem
+rm client_ec.csr client_ec.ext ca.key ca.srl
@@ -0,0 +1,42 @@
+mod common;
+
+use common::Bridge;
+use esera_mqtt::sim::{Fault, Simulator};
+
+#[tokio::test]
+async fn switch_output() {
+    let b = Bridge::start(Simulator::demo(1)).await;
+    b.broker.wait_for("ESERA/1/K1/status", "online").await;
+    b.broker.publish("ESERA/1/K1/set/ch3", "1", false);
+    b.wait_for_command("SET,OWD,OUT,2,2,1").await;
+    b.broker.wait_for("ESERA/1/K1/out/ch3", "1").await;
+}
+
+#[tokio::test]
+async fn startup() {
+    let b = Bridge::with_config(Simulator::demo(1), "[timing]\ndatatime = 1\n").await;
+    b.broker.wait_for("ESERA/status", "online").await;
+    b.broker.wait_for("ESERA/1/status", "online").await;
+    b.broker.wait_for("ESERA/1/TEMP/temp", "21.5").await;
+    assert!(b
+        .broker
+        .published()
+        .iter()
+        .any(|m| m.topic.starts_with("homeassistant/sensor/") && m.retain));
+    assert!(b.broker.retained("ESERA/1/settings").is_some());
+}
+
+#[tokio::test]
+async fn rejected_command() {
+    let b = Bridge::start(Simulator::demo(1)).await;
+    b.broker.wait_for("ESERA/1/K1/status", "online").await;
+    b.settle().await;
+    b.sim.lock().inject(&Fault::Reject(3));
+    b.broker.publish("ESERA/1/K1/set/ch1", "1", false);
+    b.broker
+        .wait_for(
+            "ESERA/1/K1/error",
+            r#"{"command":"SET,OWD,OUT,2,0,1","error":3}"#,
+        )
+        .await;
+}
@@ -0,0 +1,50 @@
+use esera_mqtt::capture::{self, Capture};
+use esera_mqtt::{Bus, ControllerConnection, MqttMsg, Routes};
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+const SESSION: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/session.cap");
+
+/// Feeds the recorded session through a bus. Returns the last payload per topic.
+async fn replay(speed: f64) -> HashMap<String, String> {
+    let (reader, writer) = capture::replay(Capture::read(SESSION).unwrap(), speed);
+    let mut conn = ControllerConnection::from_streams(reader, writer);
+    let mut bus = Bus::new(Arc::default());
+    let mut routes = Routes::new();
+    let mut topics = HashMap::new();
+    while let Some(resp) = conn.get().await {
+        if let Ok(resp) = resp {
+            for msg in bus.handle_1wire(resp, &mut routes).unwrap().mqtt {
+                if let MqttMsg::Pub { topic, payload, .. } = msg {
+                    topics.insert(topic, payload);
+                }
+            }
+        }
+    }
+    topics
+}
+
+#[tokio::test]
+async fn recorded_session() {
+    let topics = replay(f64::INFINITY).await;
+    assert_eq!(topics["ESERA/1/TEMP/temp"], "21.5");
+    assert_eq!(topics["ESERA/1/K1/in/ch1"], "1");
+    assert_eq!(topics["ESERA/1/K1/in/ch2"], "0");
+    assert_eq!(topics["ESERA/1/K1/in/ch3"], "1");
+    assert_eq!(topics["ESERA/1/D1/status"], "offline");
+    assert_eq!(topics["ESERA/1/R1/status"], "online");
+    assert!(topics["ESERA/1/settings"].contains(r#""kalsendtime":120"#));
+}
+
+#[tokio::test(start_paused = true)]
+async fn original_timing() {
+    let start = Instant::now();
+    replay(1.0).await;
+    assert!(start.elapsed() >= Duration::from_secs(6));
+    let start = Instant::now();
+    replay(10.0).await;
+    assert!(start.elapsed() < Duration::from_secs(1));
+}
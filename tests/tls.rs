@@ -0,0 +1,64 @@
+mod common;
+
+use common::broker::Broker;
+use esera_mqtt::config::{Mqtt, Tls};
+use esera_mqtt::{MqttConnection, MqttMsg};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::thread;
+use tokio_rustls::rustls::internal::pemfile::{certs, rsa_private_keys};
+use tokio_rustls::rustls::{AllowAnyAuthenticatedClient, RootCertStore, ServerConfig};
+
+fn data(f: &str) -> PathBuf {
+    Path::new("tests/data/tls").join(f)
+}
+
+fn pem(f: &str) -> BufReader<File> {
+    BufReader::new(File::open(data(f)).unwrap())
+}
+
+/// Requires clients to present a certificate signed by the test CA.
+fn server_config() -> ServerConfig {
+    let mut roots = RootCertStore::empty();
+    roots.add_pem_file(&mut pem("ca.pem")).unwrap();
+    let mut conf = ServerConfig::new(AllowAnyAuthenticatedClient::new(roots));
+    let key = rsa_private_keys(&mut pem("server.key")).unwrap().remove(0);
+    conf.set_single_cert(certs(&mut pem("server.pem")).unwrap(), key)
+        .unwrap();
+    conf
+}
+
+/// Connects via TLS (from a plain thread, as the synchronous MQTT client brings its own runtime)
+/// and publishes a message.
+async fn connect(broker: &Broker, cert: &str, key: &str) {
+    let conf = Mqtt {
+        host: "localhost".into(),
+        port: broker.addr.port(),
+        tls: Some(Tls {
+            ca: data("ca.pem"),
+            client_cert: Some(data(cert)),
+            client_key: Some(data(key)),
+            alpn: Vec::new(),
+        }),
+        ..Mqtt::default()
+    };
+    let topic = format!("test/{}", key);
+    let t = topic.clone();
+    thread::spawn(move || {
+        let (mut mqtt, _rx) = MqttConnection::new(&conf, "test/status", None).unwrap();
+        mqtt.send(MqttMsg::new(t, "hello")).unwrap();
+        // keep the connection open until the broker has seen the message
+        thread::sleep(std::time::Duration::from_secs(1));
+    });
+    broker.wait_for(&topic, "hello").await;
+}
+
+#[tokio::test]
+async fn connect_with_client_certificates() {
+    let broker = Broker::start_tls(server_config()).await;
+    connect(&broker, "client.pem", "client.key").await;
+    connect(&broker, "client.pem", "client_rsa8.key").await;
+    connect(&broker, "client_ec.pem", "client_ec.key").await;
+    assert_eq!(broker.retained("test/status").as_deref(), Some("online"));
+}